@@ -0,0 +1,472 @@
+use std::mem;
+use cgmath::{Deg, EuclideanSpace, InnerSpace, Matrix3, Matrix4, Rad, SquareMatrix, Vector3, Zero};
+use crate::bounds::{Aabb, BoundingSphere, Frustum, Ray};
+use crate::layout::Layout;
+use crate::model::{self, GPUInstance};
+use crate::scene::{InstanceBatch, NodeId, ObjectId, Scene};
+
+// the invocations per workgroup of the compute shader
+const WORKGROUP_SIZE: u32 = 64;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct AnimationUniform {
+    count: u32,
+    angle: f32,
+    _padding: [u32; 2],
+    parent: [[f32; 4]; 4],
+    // the columns are padded to four floats, like a mat3x3 in a uniform buffer
+    parent_normal: [[f32; 4]; 3],
+}
+
+/// Many instances of one object that are turned by a compute shader. The shader writes the
+/// instance data straight into a buffer that is then used as the instance vertex buffer,
+/// so only the positions are sent to the GPU, and only when the layout changes.
+///
+/// The instances belong to a node of the scene and are placed relative to it, so moving
+/// the node moves all of them. They are culled as a whole: either the box around all of
+/// them is in the view and they are all drawn, or none of them is.
+pub struct InstanceAnimation {
+    node: NodeId,
+    object: ObjectId,
+    layout: Layout,
+    // the layout changed and the positions have to be sent again
+    dirty: bool,
+    // a copy of the positions on the GPU, for picking
+    positions: Vec<Vector3<f32>>,
+    // the box around all the instances, relative to the node
+    bounds: Aabb,
+    // the world transform of the node as of the last update
+    parent: Matrix4<f32>,
+    // whether the bounds were in the view at the last update
+    visible: bool,
+    count: u32,
+    // number of instances that fit into the buffers
+    capacity: u32,
+    // the most instances the device can hold in one buffer
+    max_instances: u32,
+    max_workgroups_per_dimension: u32,
+    // how far the instances have turned, in radians
+    angle: f32,
+    uniform_buffer: wgpu::Buffer,
+    position_buffer: wgpu::Buffer,
+    instance_buffer: wgpu::Buffer,
+    bind_group_layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+    pipeline: wgpu::ComputePipeline,
+}
+
+impl InstanceAnimation {
+    /// Instances of `object` placed relative to `node`. They are in place from the start,
+    /// even if `update` is never called.
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        scene: &Scene,
+        node: NodeId,
+        object: ObjectId,
+        layout: Layout,
+    ) -> Self {
+        let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("animation uniform"),
+            size: mem::size_of::<AnimationUniform>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("animation bind group layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
+        let capacity = 1;
+        let (position_buffer, instance_buffer, bind_group) =
+            Self::create_buffers(device, &bind_group_layout, &uniform_buffer, capacity);
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Animation Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("animation.wgsl").into()),
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Animation Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("Animation Pipeline"),
+            layout: Some(&pipeline_layout),
+            module: &shader,
+            entry_point: "cs_main",
+        });
+        let mut animation = Self {
+            node,
+            object,
+            layout,
+            dirty: true,
+            positions: Vec::new(),
+            bounds: Aabb::empty(),
+            parent: scene.node(node).world(),
+            visible: true,
+            count: 0,
+            capacity,
+            max_instances: max_instances(&device.limits()),
+            max_workgroups_per_dimension: device.limits().max_compute_workgroups_per_dimension,
+            angle: 0.0,
+            uniform_buffer,
+            position_buffer,
+            instance_buffer,
+            bind_group_layout,
+            bind_group,
+            pipeline,
+        };
+        animation.update_gpu_state(scene, device, queue);
+        animation
+    }
+
+    // the buffers for the positions and the instances and the bind group that binds them
+    fn create_buffers(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        uniform_buffer: &wgpu::Buffer,
+        capacity: u32,
+    ) -> (wgpu::Buffer, wgpu::Buffer, wgpu::BindGroup) {
+        let position_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("animated position buffer"),
+            size: (capacity as usize * mem::size_of::<[f32; 4]>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let instance_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("animated instance buffer"),
+            size: (capacity as usize * mem::size_of::<GPUInstance>()) as wgpu::BufferAddress,
+            // copied out of in the tests
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("animation bind group"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: position_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: instance_buffer.as_entire_binding(),
+                },
+            ],
+        });
+        (position_buffer, instance_buffer, bind_group)
+    }
+
+    /// Turn the instances further, `speed` 1 turns them by 10° per second. The world
+    /// transforms of the scene have to be up to date, the instances follow their node and
+    /// are culled against the `frustum`.
+    pub fn update(
+        &mut self,
+        dt: instant::Duration,
+        speed: f32,
+        scene: &Scene,
+        frustum: Option<&Frustum>,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) {
+        self.angle = (self.angle + 10f32.to_radians() * dt.as_secs_f32() * speed).rem_euclid(std::f32::consts::TAU);
+        self.parent = scene.node(self.node).world();
+        self.update_gpu_state(scene, device, queue);
+        self.visible = frustum.is_none_or(|frustum| frustum.intersects_aabb(&self.bounds.transform(&self.parent)));
+    }
+
+    // Send the positions if the layout changed, the buffers grow with the number of
+    // instances and shrink again when most of them are gone
+    fn update_gpu_state(&mut self, scene: &Scene, device: &wgpu::Device, queue: &wgpu::Queue) {
+        if self.dirty {
+            self.dirty = false;
+            self.positions = self.layout.positions();
+            if self.positions.len() > self.max_instances as usize {
+                log::error!(
+                    "{} instances do not fit into the buffers of the device, only the first {} are drawn",
+                    self.positions.len(),
+                    self.max_instances,
+                );
+                self.positions.truncate(self.max_instances as usize);
+            }
+            // however an instance is turned, it stays within this distance of its position
+            let sphere = scene.object(self.object).bounding_sphere;
+            let reach = sphere.center.to_vec().magnitude() + sphere.radius;
+            let points = Aabb::from_points(self.positions.iter().map(|&p| cgmath::Point3::from_vec(p)));
+            self.bounds = if points.is_empty() {
+                points
+            } else {
+                Aabb { min: points.min - Vector3::new(reach, reach, reach), max: points.max + Vector3::new(reach, reach, reach) }
+            };
+            let positions = self.positions.iter().map(|p| [p.x, p.y, p.z, 1.0]).collect::<Vec<_>>();
+            self.count = positions.len() as u32;
+            if self.count > self.capacity || self.count * 4 < self.capacity {
+                self.capacity = self.count.max(1).next_power_of_two().min(self.max_instances);
+                (self.position_buffer, self.instance_buffer, self.bind_group) =
+                    Self::create_buffers(device, &self.bind_group_layout, &self.uniform_buffer, self.capacity);
+            }
+            if !positions.is_empty() {
+                queue.write_buffer(&self.position_buffer, 0, bytemuck::cast_slice(&positions));
+            }
+        }
+        let normal = model::normal_matrix(self.parent);
+        let uniform = AnimationUniform {
+            count: self.count,
+            angle: self.angle,
+            _padding: [0; 2],
+            parent: self.parent.into(),
+            parent_normal: [normal.x.extend(0.0).into(), normal.y.extend(0.0).into(), normal.z.extend(0.0).into()],
+        };
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+    }
+
+    /// Choose and edit the layout and the number of instances
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        self.dirty |= self.layout.ui(ui);
+        ui.label(format!("{} animated instances", self.count));
+    }
+
+    /// Record the compute pass that writes the instances, it has to run before they are drawn
+    pub fn compute(&self, encoder: &mut wgpu::CommandEncoder) {
+        if self.count == 0 {
+            return;
+        }
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Animation Pass"),
+        });
+        compute_pass.set_pipeline(&self.pipeline);
+        compute_pass.set_bind_group(0, &self.bind_group, &[]);
+        let (x, y) = workgroups(self.count, self.max_workgroups_per_dimension);
+        compute_pass.dispatch_workgroups(x, y, 1);
+    }
+
+    /// The node the instances are placed relative to
+    pub fn node(&self) -> NodeId {
+        self.node
+    }
+
+    pub fn object(&self) -> ObjectId {
+        self.object
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    pub fn instance_buffer(&self) -> &wgpu::Buffer {
+        &self.instance_buffer
+    }
+
+    /// The object, the buffer that holds its instances and the range of them to draw
+    pub fn batch<'a>(&'a self, scene: &'a Scene) -> InstanceBatch<'a> {
+        (scene.object(self.object), &self.instance_buffer, 0..self.count)
+    }
+
+    /// Like `batch`, but nothing if the instances are outside the frustum
+    pub fn visible_batch<'a>(&'a self, scene: &'a Scene) -> Option<InstanceBatch<'a>> {
+        self.visible.then(|| self.batch(scene))
+    }
+
+    /// The number of instances that are inside and outside the frustum, as of the last call
+    /// to `update`
+    pub fn culling_stats(&self) -> (usize, usize) {
+        if self.visible {
+            (self.count as usize, 0)
+        } else {
+            (0, self.count as usize)
+        }
+    }
+
+    /// The world transform of an instance as the compute shader last placed it
+    pub fn transform(&self, index: u32) -> Matrix4<f32> {
+        self.parent * instance_transform(self.positions[index as usize], self.angle)
+    }
+
+    /// The bounding sphere of an instance in world space
+    pub fn world_bounds(&self, scene: &Scene, index: u32) -> BoundingSphere {
+        scene.object(self.object).bounding_sphere.transform(&self.transform(index))
+    }
+
+    /// The closest instance that the ray hits and the distance to it along the ray, like
+    /// `Scene::pick`. The transforms of the instances are built again on the CPU for this.
+    pub fn pick(&self, scene: &Scene, ray: &Ray) -> Option<(u32, f32)> {
+        let object = scene.object(self.object);
+        let local_ray = ray.transform(&self.parent.invert()?);
+        local_ray.intersect_aabb(&self.bounds)?;
+        let mut closest: Option<(u32, f32)> = None;
+        for (index, &position) in self.positions.iter().enumerate() {
+            let transform = instance_transform(position, self.angle);
+            match local_ray.intersect_aabb(&object.aabb.transform(&transform)) {
+                Some(distance) if closest.is_none_or(|(_, closest)| distance < closest) => {}
+                _ => continue,
+            }
+            let Some(to_object) = transform.invert() else { continue };
+            if let Some(distance) = object.intersect_ray(&local_ray.transform(&to_object)) {
+                if closest.is_none_or(|(_, closest)| distance < closest) {
+                    closest = Some((index as u32, distance));
+                }
+            }
+        }
+        closest
+    }
+}
+
+// the most instances whose data fits into one storage buffer binding
+fn max_instances(limits: &wgpu::Limits) -> u32 {
+    let size = (limits.max_storage_buffer_binding_size as u64).min(limits.max_buffer_size);
+    (size / mem::size_of::<GPUInstance>() as u64).min(u32::MAX as u64) as u32
+}
+
+// the number of workgroups in x and y for `count` instances, y is only used when there
+// are more workgroups than fit into x
+fn workgroups(count: u32, max_per_dimension: u32) -> (u32, u32) {
+    let groups = count.div_ceil(WORKGROUP_SIZE);
+    if groups <= max_per_dimension {
+        (groups, 1)
+    } else {
+        (max_per_dimension, groups.div_ceil(max_per_dimension))
+    }
+}
+
+/// The transform of an instance relative to its node, the same one that `cs_main` in
+/// animation.wgsl builds
+fn instance_transform(position: Vector3<f32>, angle: f32) -> Matrix4<f32> {
+    // every instance is tilted away from the center, the one in the center has no
+    // direction to tilt in
+    let tilt = if position.is_zero() {
+        Matrix3::identity()
+    } else {
+        Matrix3::from_axis_angle(position.normalize(), Deg(45.0))
+    };
+    let rotation = tilt * Matrix3::from_angle_z(Rad(angle));
+    Matrix4::from_translation(position) * Matrix4::from(rotation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::headless::HeadlessRenderer;
+
+    // the instance data the compute shader wrote, as the model matrix and the normal matrix
+    fn read_instances(device: &wgpu::Device, queue: &wgpu::Queue, animation: &InstanceAnimation) -> Vec<[f32; 28]> {
+        let size = (animation.count as usize * mem::size_of::<GPUInstance>()) as wgpu::BufferAddress;
+        let staging = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("animation readback"),
+            size,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        animation.compute(&mut encoder);
+        encoder.copy_buffer_to_buffer(&animation.instance_buffer, 0, &staging, 0, size);
+        queue.submit(std::iter::once(encoder.finish()));
+        staging.slice(..).map_async(wgpu::MapMode::Read, |result| result.unwrap());
+        device.poll(wgpu::Maintain::Wait);
+        // bound so that the view is dropped before the buffer it maps
+        let mapped = staging.slice(..).get_mapped_range();
+        bytemuck::cast_slice(&mapped).to_vec()
+    }
+
+    #[test]
+    fn animation_uniform_matches_the_shader() {
+        // the matrices of struct Animation in animation.wgsl are 16 byte aligned, and each
+        // column of the mat3x3 takes four floats
+        assert_eq!(mem::offset_of!(AnimationUniform, parent), 16);
+        assert_eq!(mem::offset_of!(AnimationUniform, parent_normal), 80);
+        assert_eq!(mem::size_of::<AnimationUniform>(), 128);
+    }
+
+    #[test]
+    fn workgroups_spill_into_y() {
+        assert_eq!(workgroups(0, 65535), (0, 1));
+        assert_eq!(workgroups(100, 65535), (2, 1));
+        assert_eq!(workgroups(64 * 65535, 65535), (65535, 1));
+        assert_eq!(workgroups(64 * 65535 + 1, 65535), (65535, 2));
+        assert_eq!(workgroups(64 * 10, 4), (4, 3));
+    }
+
+    #[test]
+    fn instances_fit_into_a_binding() {
+        let limits = wgpu::Limits::downlevel_defaults();
+        let max = max_instances(&limits) as u64;
+        assert!(max * mem::size_of::<GPUInstance>() as u64 <= limits.max_storage_buffer_binding_size as u64);
+        assert!((max + 1) * mem::size_of::<GPUInstance>() as u64 > limits.max_storage_buffer_binding_size as u64);
+    }
+
+    #[test]
+    fn shader_matches_the_cpu_transforms() {
+        let Ok(mut headless) = pollster::block_on(HeadlessRenderer::new(64, 64, false)) else {
+            eprintln!("no adapter, skipping");
+            return;
+        };
+        let renderer = &mut headless.renderer;
+        // move the node, so the parent transform takes part as well
+        let node = renderer.animation.as_ref().unwrap().node();
+        let transform = &mut renderer.scene.node_mut(node).transform;
+        transform.position = Vector3::new(1.0, -2.0, 0.5);
+        transform.scale = Vector3::new(1.0, 2.0, 0.5);
+        renderer.scene.update_world_transforms();
+        let animation = renderer.animation.as_mut().unwrap();
+        animation.layout = Layout::Spiral { count: 300, spacing: 1.5 };
+        animation.dirty = true;
+        // the 5 workgroups are spread over x and y
+        animation.max_workgroups_per_dimension = 2;
+        animation.update(instant::Duration::from_secs(3), 1.0, &renderer.scene, None, &renderer.device, &renderer.queue);
+
+        let instances = read_instances(&renderer.device, &renderer.queue, animation);
+        assert_eq!(instances.len(), 300);
+        for (index, instance) in instances.iter().enumerate() {
+            let transform = animation.transform(index as u32);
+            let normal = model::normal_matrix(transform);
+            for column in 0..4 {
+                let start = column * 4;
+                let gpu = cgmath::Vector4::new(instance[start], instance[start + 1], instance[start + 2], instance[start + 3]);
+                assert!((gpu - transform[column]).magnitude() < 1e-4, "instance {}: {:?} != {:?}", index, gpu, transform[column]);
+            }
+            for column in 0..3 {
+                let start = 16 + column * 4;
+                let gpu = Vector3::new(instance[start], instance[start + 1], instance[start + 2]);
+                assert!((gpu - normal[column]).magnitude() < 1e-4, "instance {}: {:?} != {:?}", index, gpu, normal[column]);
+            }
+        }
+    }
+}
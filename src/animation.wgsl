@@ -0,0 +1,65 @@
+// Places the animated instances at their positions and turns them, one invocation per
+// instance. The output is the instance data the scene shaders read as vertex attributes
+// 5 to 11. `instance_transform` in animation.rs builds the same transform on the CPU for
+// picking, the two have to be changed together.
+
+struct Instance {
+    transform: mat4x4<f32>,
+    // the inverse transpose of the upper 3x3 of the transform
+    normal: mat3x3<f32>,
+}
+
+struct Animation {
+    count: u32,
+    // how far every instance has turned around its z axis, in radians
+    angle: f32,
+    // the world transform of the node the instances belong to and its normal matrix
+    parent: mat4x4<f32>,
+    parent_normal: mat3x3<f32>,
+}
+
+@group(0) @binding(0)
+var<uniform> animation: Animation;
+// the positions from the layout, w is 1
+@group(0) @binding(1)
+var<storage, read> positions: array<vec4<f32>>;
+@group(0) @binding(2)
+var<storage, read_write> instances: array<Instance>;
+
+// the rotation around a unit axis
+fn rotation(axis: vec3<f32>, angle: f32) -> mat3x3<f32> {
+    let c = cos(angle);
+    let s = sin(angle);
+    let t = 1.0 - c;
+    let a = axis;
+    return mat3x3<f32>(
+        vec3<f32>(t * a.x * a.x + c, t * a.x * a.y + s * a.z, t * a.x * a.z - s * a.y),
+        vec3<f32>(t * a.x * a.y - s * a.z, t * a.y * a.y + c, t * a.y * a.z + s * a.x),
+        vec3<f32>(t * a.x * a.z + s * a.y, t * a.y * a.z - s * a.x, t * a.z * a.z + c),
+    );
+}
+
+// the workgroups that do not fit into x are spread over y
+@compute @workgroup_size(64)
+fn cs_main(@builtin(global_invocation_id) id: vec3<u32>, @builtin(num_workgroups) workgroups: vec3<u32>) {
+    let i = id.x + id.y * workgroups.x * 64u;
+    if i >= animation.count {
+        return;
+    }
+    let position = positions[i].xyz;
+    // every instance is tilted away from the center, the one in the center has no
+    // direction to tilt in
+    var tilt = mat3x3<f32>(vec3<f32>(1.0, 0.0, 0.0), vec3<f32>(0.0, 1.0, 0.0), vec3<f32>(0.0, 0.0, 1.0));
+    if any(position != vec3<f32>(0.0)) {
+        tilt = rotation(normalize(position), radians(45.0));
+    }
+    let r = tilt * rotation(vec3<f32>(0.0, 0.0, 1.0), animation.angle);
+    instances[i].transform = animation.parent * mat4x4<f32>(
+        vec4<f32>(r[0], 0.0),
+        vec4<f32>(r[1], 0.0),
+        vec4<f32>(r[2], 0.0),
+        vec4<f32>(position, 1.0),
+    );
+    // a rotation is its own inverse transpose
+    instances[i].normal = animation.parent_normal * r;
+}
@@ -0,0 +1,422 @@
+use cgmath::{EuclideanSpace, InnerSpace, Matrix4, Point3, Transform, Vector3, Vector4};
+
+/// An axis aligned bounding box
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Aabb {
+    pub min: Point3<f32>,
+    pub max: Point3<f32>,
+}
+
+impl Aabb {
+    /// The smallest box around the points. Without points the box is empty and has its
+    /// minimum above its maximum, so that it is swallowed by `union`.
+    pub fn from_points<I: IntoIterator<Item = Point3<f32>>>(points: I) -> Self {
+        points.into_iter().fold(Self::empty(), |aabb, p| aabb.union(&Self { min: p, max: p }))
+    }
+
+    pub fn empty() -> Self {
+        Self {
+            min: Point3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+            max: Point3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    pub fn center(&self) -> Point3<f32> {
+        self.min.midpoint(self.max)
+    }
+
+    /// Half of the size of the box along every axis
+    pub fn half_extents(&self) -> Vector3<f32> {
+        (self.max - self.min) / 2.0
+    }
+
+    pub fn corners(&self) -> [Point3<f32>; 8] {
+        let (a, b) = (self.min, self.max);
+        [
+            Point3::new(a.x, a.y, a.z),
+            Point3::new(b.x, a.y, a.z),
+            Point3::new(a.x, b.y, a.z),
+            Point3::new(b.x, b.y, a.z),
+            Point3::new(a.x, a.y, b.z),
+            Point3::new(b.x, a.y, b.z),
+            Point3::new(a.x, b.y, b.z),
+            Point3::new(b.x, b.y, b.z),
+        ]
+    }
+
+    /// The smallest box around both boxes
+    pub fn union(&self, other: &Aabb) -> Self {
+        Self {
+            min: Point3::new(self.min.x.min(other.min.x), self.min.y.min(other.min.y), self.min.z.min(other.min.z)),
+            max: Point3::new(self.max.x.max(other.max.x), self.max.y.max(other.max.y), self.max.z.max(other.max.z)),
+        }
+    }
+
+    /// The box around this box after it was transformed, it is bigger than the transformed
+    /// box itself if the transform rotates it.
+    pub fn transform(&self, transform: &Matrix4<f32>) -> Self {
+        if self.is_empty() {
+            return *self;
+        }
+        Self::from_points(self.corners().iter().map(|&corner| transform.transform_point(corner)))
+    }
+}
+
+/// A sphere around an object, cheaper to test against than a box but usually less tight
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct BoundingSphere {
+    pub center: Point3<f32>,
+    pub radius: f32,
+}
+
+impl BoundingSphere {
+    /// A sphere around the points that is centered on their bounding box. This is not the
+    /// smallest possible sphere, but never more than the box around it.
+    pub fn from_points<I: IntoIterator<Item = Point3<f32>> + Clone>(points: I) -> Self {
+        let aabb = Aabb::from_points(points.clone());
+        if aabb.is_empty() {
+            return Self { center: Point3::origin(), radius: 0.0 };
+        }
+        let center = aabb.center();
+        let radius = points.into_iter().map(|p| (p - center).magnitude()).fold(0.0, f32::max);
+        Self { center, radius }
+    }
+
+    /// The smallest sphere around both spheres
+    pub fn union(&self, other: &BoundingSphere) -> Self {
+        let offset = other.center - self.center;
+        let distance = offset.magnitude();
+        if distance + other.radius <= self.radius {
+            return *self;
+        }
+        if distance + self.radius <= other.radius {
+            return *other;
+        }
+        let radius = (distance + self.radius + other.radius) / 2.0;
+        let center = self.center + offset * ((radius - self.radius) / distance);
+        Self { center, radius }
+    }
+
+    /// The sphere around this sphere after it was transformed. With a non uniform scale the
+    /// sphere becomes an ellipsoid, so the radius grows with the largest scale.
+    pub fn transform(&self, transform: &Matrix4<f32>) -> Self {
+        let scale = [transform.x, transform.y, transform.z]
+            .iter()
+            .map(|axis| axis.truncate().magnitude())
+            .fold(0.0, f32::max);
+        Self {
+            center: transform.transform_point(self.center),
+            radius: self.radius * scale,
+        }
+    }
+}
+
+/// A half line, `direction` does not have to be normalized. Distances along the ray are
+/// measured in multiples of `direction`, so they stay the same when the ray is transformed.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Ray {
+    pub origin: Point3<f32>,
+    pub direction: Vector3<f32>,
+}
+
+impl Ray {
+    pub fn transform(&self, transform: &Matrix4<f32>) -> Self {
+        Self {
+            origin: transform.transform_point(self.origin),
+            direction: transform.transform_vector(self.direction),
+        }
+    }
+
+    /// Where the ray enters the box (slab test), 0 if it starts inside of it
+    pub fn intersect_aabb(&self, aabb: &Aabb) -> Option<f32> {
+        let mut near = 0.0f32;
+        let mut far = f32::INFINITY;
+        for axis in 0..3 {
+            // a direction of 0 gives infinities that do the right thing
+            let inverse = 1.0 / self.direction[axis];
+            let t0 = (aabb.min[axis] - self.origin[axis]) * inverse;
+            let t1 = (aabb.max[axis] - self.origin[axis]) * inverse;
+            near = near.max(t0.min(t1));
+            far = far.min(t0.max(t1));
+        }
+        (near <= far).then_some(near)
+    }
+
+    /// Where the ray hits the triangle, from either side (Möller–Trumbore)
+    pub fn intersect_triangle(&self, a: Point3<f32>, b: Point3<f32>, c: Point3<f32>) -> Option<f32> {
+        let edge1 = b - a;
+        let edge2 = c - a;
+        let p = self.direction.cross(edge2);
+        let determinant = edge1.dot(p);
+        if determinant.abs() < f32::EPSILON * edge1.magnitude() * edge2.magnitude() {
+            // the ray is parallel to the triangle
+            return None;
+        }
+        let to_origin = self.origin - a;
+        let u = to_origin.dot(p) / determinant;
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+        let q = to_origin.cross(edge1);
+        let v = self.direction.dot(q) / determinant;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+        let distance = edge2.dot(q) / determinant;
+        (distance >= 0.0).then_some(distance)
+    }
+}
+
+/// The volume a camera sees, as six planes that face inwards. The xyz of a plane is its
+/// normal and w the distance, a point p is on the inner side if dot(xyz, p) + w >= 0.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Frustum {
+    planes: [Vector4<f32>; 6],
+}
+
+impl Frustum {
+    /// Extract the planes from a view projection matrix (Gribb and Hartmann). The clip space
+    /// depth goes from 0 to 1 like it does in wgpu, either from the near to the far plane or
+    /// the other way around with reverse-Z. A far plane at infinity keeps everything.
+    pub fn from_matrix(view_proj: &Matrix4<f32>) -> Self {
+        let row = |i: usize| Vector4::new(view_proj.x[i], view_proj.y[i], view_proj.z[i], view_proj.w[i]);
+        let planes = [
+            row(3) + row(0), // left
+            row(3) - row(0), // right
+            row(3) + row(1), // bottom
+            row(3) - row(1), // top
+            row(2),          // near (far with reverse-Z)
+            row(3) - row(2), // far (near with reverse-Z)
+        ];
+        // the plane at infinity has no normal, only its w that is positive everywhere
+        let normalize = |plane: Vector4<f32>| match plane.truncate().magnitude() {
+            length if length > 0.0 => plane / length,
+            _ => plane,
+        };
+        Self { planes: planes.map(normalize) }
+    }
+
+    fn distance(plane: &Vector4<f32>, point: Point3<f32>) -> f32 {
+        plane.truncate().dot(point.to_vec()) + plane.w
+    }
+
+    pub fn intersects_sphere(&self, sphere: &BoundingSphere) -> bool {
+        self.planes.iter().all(|plane| Self::distance(plane, sphere.center) >= -sphere.radius)
+    }
+
+    /// Whether some part of the box may be inside. Boxes that are outside all planes near a
+    /// corner of the frustum are not detected, they are drawn even though they are not visible.
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|plane| {
+            // the corner of the box that is furthest along the normal of the plane
+            let corner = Point3::new(
+                if plane.x >= 0.0 { aabb.max.x } else { aabb.min.x },
+                if plane.y >= 0.0 { aabb.max.y } else { aabb.min.y },
+                if plane.z >= 0.0 { aabb.max.z } else { aabb.min.z },
+            );
+            Self::distance(plane, corner) >= 0.0
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Deg, Quaternion, Rotation3, SquareMatrix};
+    use crate::model::Instance;
+    use crate::observer::{reverse_infinite_perspective, OPENGL_TO_WGPU_MATRIX};
+
+    const EPSILON: f32 = 1e-5;
+
+    fn cube_points() -> Vec<Point3<f32>> {
+        Aabb { min: Point3::new(-1.0, -1.0, -1.0), max: Point3::new(1.0, 1.0, 1.0) }.corners().to_vec()
+    }
+
+    fn assert_close(a: Point3<f32>, b: Point3<f32>) {
+        assert!((a - b).magnitude() < EPSILON, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn aabb_of_points() {
+        let aabb = Aabb::from_points([
+            Point3::new(1.0, -2.0, 0.5),
+            Point3::new(-3.0, 4.0, 0.0),
+            Point3::new(0.0, 0.0, 2.0),
+        ]);
+        assert_eq!(aabb.min, Point3::new(-3.0, -2.0, 0.0));
+        assert_eq!(aabb.max, Point3::new(1.0, 4.0, 2.0));
+        assert_eq!(aabb.center(), Point3::new(-1.0, 1.0, 1.0));
+        assert_eq!(aabb.half_extents(), Vector3::new(2.0, 3.0, 1.0));
+    }
+
+    #[test]
+    fn empty_aabb_is_swallowed_by_union() {
+        let empty = Aabb::from_points([]);
+        assert!(empty.is_empty());
+        let aabb = Aabb::from_points(cube_points());
+        assert_eq!(empty.union(&aabb), aabb);
+        assert_eq!(aabb.union(&empty), aabb);
+        assert!(empty.transform(&Matrix4::from_scale(2.0)).is_empty());
+    }
+
+    #[test]
+    fn aabb_follows_the_instance_transform() {
+        let instance = Instance {
+            position: Vector3::new(10.0, 0.0, -5.0),
+            rotation: Quaternion::from_angle_y(Deg(45.0)),
+            scale: Vector3::new(1.0, 2.0, 1.0),
+        };
+        let aabb = Aabb::from_points(cube_points()).transform(&instance.to_matrix());
+        let diagonal = 2.0f32.sqrt();
+        assert_close(aabb.min, Point3::new(10.0 - diagonal, -2.0, -5.0 - diagonal));
+        assert_close(aabb.max, Point3::new(10.0 + diagonal, 2.0, -5.0 + diagonal));
+        // the world box has to contain every transformed vertex
+        for p in cube_points() {
+            let p = instance.to_matrix().transform_point(p);
+            for axis in 0..3 {
+                assert!(p[axis] >= aabb.min[axis] - EPSILON && p[axis] <= aabb.max[axis] + EPSILON, "{:?} is outside", p);
+            }
+        }
+    }
+
+    #[test]
+    fn sphere_of_points() {
+        let sphere = BoundingSphere::from_points(cube_points());
+        assert_eq!(sphere.center, Point3::origin());
+        assert!((sphere.radius - 3.0f32.sqrt()).abs() < EPSILON);
+        for p in cube_points() {
+            assert!((p - sphere.center).magnitude() <= sphere.radius + EPSILON);
+        }
+        assert_eq!(BoundingSphere::from_points(Vec::new()).radius, 0.0);
+    }
+
+    #[test]
+    fn sphere_union_contains_both() {
+        let a = BoundingSphere { center: Point3::new(0.0, 0.0, 0.0), radius: 1.0 };
+        let b = BoundingSphere { center: Point3::new(4.0, 0.0, 0.0), radius: 2.0 };
+        let union = a.union(&b);
+        assert_close(union.center, Point3::new(2.5, 0.0, 0.0));
+        assert!((union.radius - 3.5).abs() < EPSILON);
+        // a sphere inside the other one does not change it
+        let inner = BoundingSphere { center: Point3::new(4.5, 0.0, 0.0), radius: 0.5 };
+        assert_eq!(b.union(&inner), b);
+        assert_eq!(inner.union(&b), b);
+    }
+
+    #[test]
+    fn sphere_follows_the_instance_transform() {
+        let instance = Instance {
+            position: Vector3::new(0.0, 3.0, 0.0),
+            rotation: Quaternion::from_angle_x(Deg(30.0)),
+            scale: Vector3::new(1.0, 3.0, 0.5),
+        };
+        let sphere = BoundingSphere::from_points(cube_points()).transform(&instance.to_matrix());
+        assert_close(sphere.center, Point3::new(0.0, 3.0, 0.0));
+        assert!((sphere.radius - 3.0 * 3.0f32.sqrt()).abs() < EPSILON);
+        for p in cube_points() {
+            let p = instance.to_matrix().transform_point(p);
+            assert!((p - sphere.center).magnitude() <= sphere.radius + EPSILON);
+        }
+    }
+
+    // a camera at the origin that looks down -z with a 90° field of view
+    fn frustum() -> Frustum {
+        let projection = OPENGL_TO_WGPU_MATRIX * cgmath::perspective(Deg(90.0), 1.0, 0.1, 100.0);
+        Frustum::from_matrix(&projection)
+    }
+
+    fn unit_box_at(x: f32, y: f32, z: f32) -> Aabb {
+        Aabb { min: Point3::new(x - 0.5, y - 0.5, z - 0.5), max: Point3::new(x + 0.5, y + 0.5, z + 0.5) }
+    }
+
+    #[test]
+    fn frustum_keeps_what_is_in_front() {
+        let frustum = frustum();
+        for (x, y, z) in [(0.0, 0.0, -5.0), (4.0, 0.0, -5.0), (0.0, -5.0, -5.0), (0.0, 0.0, -99.0)] {
+            let aabb = unit_box_at(x, y, z);
+            assert!(frustum.intersects_aabb(&aabb), "box at {:?} was culled", (x, y, z));
+            let sphere = BoundingSphere::from_points(aabb.corners());
+            assert!(frustum.intersects_sphere(&sphere), "sphere at {:?} was culled", (x, y, z));
+        }
+    }
+
+    #[test]
+    fn frustum_culls_what_is_outside() {
+        let frustum = frustum();
+        // behind, left, right, below, above and beyond the far plane
+        for (x, y, z) in [(0.0, 0.0, 5.0), (-7.0, 0.0, -5.0), (7.0, 0.0, -5.0), (0.0, -7.0, -5.0), (0.0, 7.0, -5.0), (0.0, 0.0, -102.0)] {
+            let aabb = unit_box_at(x, y, z);
+            assert!(!frustum.intersects_aabb(&aabb), "box at {:?} was not culled", (x, y, z));
+            let sphere = BoundingSphere::from_points(aabb.corners());
+            assert!(!frustum.intersects_sphere(&sphere), "sphere at {:?} was not culled", (x, y, z));
+        }
+    }
+
+    #[test]
+    fn frustum_moves_with_the_camera() {
+        let view = Matrix4::look_to_rh(Point3::new(50.0, 0.0, 0.0), Vector3::unit_x(), Vector3::unit_y());
+        let projection = OPENGL_TO_WGPU_MATRIX * cgmath::perspective(Deg(90.0), 1.0, 0.1, 100.0);
+        let frustum = Frustum::from_matrix(&(projection * view));
+        assert!(frustum.intersects_aabb(&unit_box_at(60.0, 0.0, 0.0)));
+        assert!(!frustum.intersects_aabb(&unit_box_at(0.0, 0.0, -5.0)));
+    }
+
+    #[test]
+    fn reverse_infinite_frustum_has_no_far_plane() {
+        let projection = reverse_infinite_perspective(Deg(90.0), 1.0, 0.1);
+        let frustum = Frustum::from_matrix(&projection);
+        assert!(frustum.intersects_aabb(&unit_box_at(0.0, 0.0, -5.0)));
+        assert!(frustum.intersects_aabb(&unit_box_at(0.0, 0.0, -1.0e6)));
+        assert!(frustum.intersects_sphere(&BoundingSphere { center: Point3::new(0.0, 0.0, -1.0e6), radius: 1.0 }));
+        assert!(!frustum.intersects_aabb(&unit_box_at(0.0, 0.0, 5.0)));
+        assert!(!frustum.intersects_aabb(&unit_box_at(0.0, 100.0, -5.0)));
+    }
+
+    #[test]
+    fn ray_hits_the_box() {
+        let aabb = unit_box_at(0.0, 0.0, -5.0);
+        let ray = Ray { origin: Point3::origin(), direction: -Vector3::unit_z() };
+        assert!((ray.intersect_aabb(&aabb).unwrap() - 4.5).abs() < EPSILON);
+        // starting inside the box
+        let inside = Ray { origin: Point3::new(0.0, 0.0, -5.0), direction: Vector3::unit_x() };
+        assert_eq!(inside.intersect_aabb(&aabb), Some(0.0));
+        // parallel to a face, pointing away and passing by
+        assert_eq!(Ray { origin: Point3::new(0.0, 2.0, 0.0), direction: -Vector3::unit_z() }.intersect_aabb(&aabb), None);
+        assert_eq!(Ray { origin: Point3::origin(), direction: Vector3::unit_z() }.intersect_aabb(&aabb), None);
+        assert_eq!(Ray { origin: Point3::origin(), direction: Vector3::new(1.0, 0.0, -1.0) }.intersect_aabb(&aabb), None);
+    }
+
+    #[test]
+    fn ray_hits_the_triangle() {
+        let (a, b, c) = (Point3::new(-1.0, -1.0, -3.0), Point3::new(1.0, -1.0, -3.0), Point3::new(0.0, 1.0, -3.0));
+        let ray = Ray { origin: Point3::origin(), direction: -Vector3::unit_z() };
+        assert!((ray.intersect_triangle(a, b, c).unwrap() - 3.0).abs() < EPSILON);
+        // the back side is hit as well
+        assert!((ray.intersect_triangle(a, c, b).unwrap() - 3.0).abs() < EPSILON);
+        // behind the origin, next to the triangle and parallel to it
+        assert_eq!(Ray { origin: Point3::origin(), direction: Vector3::unit_z() }.intersect_triangle(a, b, c), None);
+        assert_eq!(Ray { origin: Point3::new(2.0, 0.0, 0.0), direction: -Vector3::unit_z() }.intersect_triangle(a, b, c), None);
+        assert_eq!(Ray { origin: Point3::origin(), direction: Vector3::unit_x() }.intersect_triangle(a, b, c), None);
+    }
+
+    #[test]
+    fn distances_survive_the_transform() {
+        let instance = Instance {
+            position: Vector3::new(3.0, 0.0, -4.0),
+            rotation: Quaternion::from_angle_y(Deg(30.0)),
+            scale: Vector3::new(2.0, 2.0, 2.0),
+        };
+        let world = Ray { origin: Point3::new(3.0, 0.5, 10.0), direction: -Vector3::unit_z() };
+        let local = world.transform(&instance.to_matrix().invert().unwrap());
+        let aabb = Aabb::from_points(cube_points());
+        let distance = local.intersect_aabb(&aabb).unwrap();
+        let local_hit = local.origin + local.direction * distance;
+        assert_close(instance.to_matrix().transform_point(local_hit), world.origin + world.direction * distance);
+        // the bounds of the rotated box are hit before the box itself
+        assert!(world.intersect_aabb(&aabb.transform(&instance.to_matrix())).unwrap() < distance);
+    }
+}
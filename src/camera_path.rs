@@ -0,0 +1,383 @@
+use std::path::{Path, PathBuf};
+use anyhow::Context;
+use cgmath::{Deg, Point3};
+use serde::{Deserialize, Serialize};
+use crate::observer::Camera;
+
+/// Where the camera is and where it looks at a point in time. The angles are in degrees.
+/// The yaw of the camera is not wrapped around, so interpolating it directly turns the
+/// camera the same way it turned while it was recorded.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Keyframe {
+    /// seconds from the start of the path
+    pub time: f32,
+    pub position: [f32; 3],
+    pub yaw: f32,
+    pub pitch: f32,
+    pub field_of_view: f32,
+}
+
+impl Keyframe {
+    pub fn from_camera(time: f32, camera: &Camera) -> Self {
+        Self {
+            time,
+            position: camera.position.into(),
+            yaw: Deg::from(camera.yaw()).0,
+            pitch: Deg::from(camera.pitch()).0,
+            field_of_view: Deg::from(camera.projection.field_of_view()).0,
+        }
+    }
+
+    /// Move the camera to the keyframe, the clip planes stay as they are
+    pub fn apply(&self, camera: &mut Camera, queue: &wgpu::Queue) {
+        camera.set_view(Point3::from(self.position), Deg(self.yaw), Deg(self.pitch));
+        let (znear, zfar) = (camera.projection.znear(), camera.projection.zfar());
+        camera.projection.set_perspective(Deg(self.field_of_view), znear, zfar);
+        camera.update_gpu_state(queue);
+    }
+
+    fn values(&self) -> [f32; 6] {
+        let [x, y, z] = self.position;
+        [x, y, z, self.yaw, self.pitch, self.field_of_view]
+    }
+
+    fn from_values(time: f32, [x, y, z, yaw, pitch, field_of_view]: [f32; 6]) -> Self {
+        Self { time, position: [x, y, z], yaw, pitch, field_of_view }
+    }
+}
+
+/// How the camera gets from one keyframe to the next
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Interpolation {
+    /// A spline through all the keyframes
+    CatmullRom,
+    /// The keyframes are the control points of one Bezier curve. The curve is smoother, but
+    /// it only passes through the first and the last keyframe.
+    Bezier,
+}
+
+/// A camera path for fly-throughs, it is stored as a RON file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CameraPath {
+    pub interpolation: Interpolation,
+    // sorted by time
+    keyframes: Vec<Keyframe>,
+}
+
+impl Default for CameraPath {
+    fn default() -> Self {
+        Self { interpolation: Interpolation::CatmullRom, keyframes: Vec::new() }
+    }
+}
+
+impl CameraPath {
+    pub fn keyframes(&self) -> &[Keyframe] {
+        &self.keyframes
+    }
+
+    /// Insert a keyframe at its time, it replaces a keyframe at the same time
+    pub fn add(&mut self, keyframe: Keyframe) {
+        match self.keyframes.binary_search_by(|k| k.time.total_cmp(&keyframe.time)) {
+            Ok(i) => self.keyframes[i] = keyframe,
+            Err(i) => self.keyframes.insert(i, keyframe),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.keyframes.clear();
+    }
+
+    /// The time of the last keyframe
+    pub fn duration(&self) -> f32 {
+        self.keyframes.last().map_or(0.0, |k| k.time)
+    }
+
+    /// Where the camera is at `time`, before the first and after the last keyframe it
+    /// stays at them. A path without keyframes has no camera.
+    pub fn sample(&self, time: f32) -> Option<Keyframe> {
+        let first = self.keyframes.first()?;
+        let last = self.keyframes.last()?;
+        if time <= first.time {
+            return Some(Keyframe { time, ..*first });
+        }
+        if time >= last.time {
+            return Some(Keyframe { time, ..*last });
+        }
+        let values = match self.interpolation {
+            Interpolation::CatmullRom => self.catmull_rom(time),
+            Interpolation::Bezier => self.bezier((time - first.time) / (last.time - first.time)),
+        };
+        Some(Keyframe::from_values(time, values))
+    }
+
+    // A cubic Hermite spline whose tangents are the slopes between the neighbouring
+    // keyframes, so that keyframes at uneven intervals do not make the camera jerk
+    fn catmull_rom(&self, time: f32) -> [f32; 6] {
+        let k = &self.keyframes;
+        // the segment that contains the time, there is one as the time is inside the path
+        let i = k.partition_point(|k| k.time <= time) - 1;
+        let tangent = |i: usize| -> [f32; 6] {
+            let (before, after) = (&k[i.saturating_sub(1)], &k[(i + 1).min(k.len() - 1)]);
+            let (a, b) = (before.values(), after.values());
+            std::array::from_fn(|c| (b[c] - a[c]) / (after.time - before.time))
+        };
+        let (start, end) = (&k[i], &k[i + 1]);
+        let h = end.time - start.time;
+        let s = (time - start.time) / h;
+        let (s2, s3) = (s * s, s * s * s);
+        let (m0, m1) = (tangent(i), tangent(i + 1));
+        let (p0, p1) = (start.values(), end.values());
+        std::array::from_fn(|c| {
+            (2.0 * s3 - 3.0 * s2 + 1.0) * p0[c]
+                + (s3 - 2.0 * s2 + s) * h * m0[c]
+                + (-2.0 * s3 + 3.0 * s2) * p1[c]
+                + (s3 - s2) * h * m1[c]
+        })
+    }
+
+    // de Casteljau's algorithm over all the keyframes, u goes from 0 to 1
+    fn bezier(&self, u: f32) -> [f32; 6] {
+        let mut points = self.keyframes.iter().map(Keyframe::values).collect::<Vec<_>>();
+        while points.len() > 1 {
+            points = points.windows(2)
+                .map(|pair| std::array::from_fn(|c| pair[0][c] + (pair[1][c] - pair[0][c]) * u))
+                .collect();
+        }
+        points[0]
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).with_context(|| format!("failed to read {:?}", path))?;
+        let mut camera_path: Self = ron::from_str(&text).with_context(|| format!("failed to parse the camera path {:?}", path))?;
+        camera_path.keyframes.sort_by(|a, b| a.time.total_cmp(&b.time));
+        Ok(camera_path)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let text = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?;
+        std::fs::write(path, text).with_context(|| format!("failed to write {:?}", path))
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum State {
+    Idle,
+    /// a keyframe is taken every `record_interval` seconds
+    Recording { time: f32, since_keyframe: f32 },
+    Playing { time: f32 },
+}
+
+/// Records and plays back a camera path. While the path is played the camera follows it
+/// and ignores the controls.
+pub struct Flythrough {
+    pub path: CameraPath,
+    /// seconds between the keyframes that are taken while recording
+    pub record_interval: f32,
+    /// start over at the end of the path
+    pub looping: bool,
+    file: PathBuf,
+    state: State,
+}
+
+impl Flythrough {
+    pub fn new<P: Into<PathBuf>>(file: P) -> Self {
+        Self {
+            path: CameraPath::default(),
+            record_interval: 0.5,
+            looping: false,
+            file: file.into(),
+            state: State::Idle,
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        matches!(self.state, State::Playing { .. })
+    }
+
+    /// Move the camera along the path while the path is played
+    pub fn play(&mut self, dt: instant::Duration, camera: &mut Camera, queue: &wgpu::Queue) {
+        let State::Playing { time } = &mut self.state else { return };
+        *time += dt.as_secs_f32();
+        if *time > self.path.duration() {
+            if self.looping && self.path.duration() > 0.0 {
+                *time %= self.path.duration();
+            } else {
+                self.state = State::Idle;
+            }
+        }
+        let time = match self.state {
+            State::Playing { time } => time,
+            _ => self.path.duration(),
+        };
+        if let Some(keyframe) = self.path.sample(time) {
+            keyframe.apply(camera, queue);
+        }
+    }
+
+    /// Take a keyframe of the camera whenever the interval has passed while recording
+    pub fn record(&mut self, dt: instant::Duration, camera: &Camera) {
+        let State::Recording { time, since_keyframe } = &mut self.state else { return };
+        *time += dt.as_secs_f32();
+        *since_keyframe += dt.as_secs_f32();
+        if *since_keyframe >= self.record_interval {
+            *since_keyframe = 0.0;
+            self.path.add(Keyframe::from_camera(*time, camera));
+        }
+    }
+
+    /// Controls for recording, playing, saving and loading the path
+    pub fn ui(&mut self, ui: &mut egui::Ui, camera: &Camera) {
+        ui.horizontal(|ui| {
+            match self.state {
+                State::Recording { time, .. } => {
+                    if ui.button("stop recording").clicked() {
+                        self.path.add(Keyframe::from_camera(time, camera));
+                        self.state = State::Idle;
+                    }
+                }
+                State::Playing { .. } => {
+                    if ui.button("stop").clicked() {
+                        self.state = State::Idle;
+                    }
+                }
+                State::Idle => {
+                    if ui.button("record").clicked() {
+                        self.path.clear();
+                        self.path.add(Keyframe::from_camera(0.0, camera));
+                        self.state = State::Recording { time: 0.0, since_keyframe: 0.0 };
+                    }
+                    if ui.add_enabled(!self.path.keyframes().is_empty(), egui::Button::new("play")).clicked() {
+                        self.state = State::Playing { time: 0.0 };
+                    }
+                }
+            }
+            ui.checkbox(&mut self.looping, "loop");
+        });
+        ui.horizontal(|ui| {
+            if ui.add_enabled(self.state == State::Idle, egui::Button::new("add keyframe")).clicked() {
+                // one second after the last keyframe
+                let time = if self.path.keyframes().is_empty() { 0.0 } else { self.path.duration() + 1.0 };
+                self.path.add(Keyframe::from_camera(time, camera));
+            }
+            if ui.add_enabled(self.state == State::Idle, egui::Button::new("clear")).clicked() {
+                self.path.clear();
+            }
+        });
+        ui.add(egui::Slider::new(&mut self.record_interval, 0.1..=5.0).text("record interval (s)"));
+        egui::ComboBox::from_label("interpolation")
+            .selected_text(format!("{:?}", self.path.interpolation))
+            .show_ui(ui, |ui| {
+                for interpolation in [Interpolation::CatmullRom, Interpolation::Bezier] {
+                    ui.selectable_value(&mut self.path.interpolation, interpolation, format!("{:?}", interpolation));
+                }
+            });
+        ui.label(format!("{} keyframes, {:.1} s", self.path.keyframes().len(), self.path.duration()));
+        ui.horizontal(|ui| {
+            if ui.button(format!("save to {}", self.file.display())).clicked() {
+                if let Err(e) = self.path.save(&self.file) {
+                    log::error!("{:?}", e);
+                }
+            }
+            if ui.add_enabled(self.state == State::Idle, egui::Button::new("load")).clicked() {
+                match CameraPath::load(&self.file) {
+                    Ok(path) => self.path = path,
+                    Err(e) => log::error!("{:?}", e),
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: f32 = 1e-4;
+
+    fn keyframe(time: f32, x: f32, yaw: f32) -> Keyframe {
+        Keyframe { time, position: [x, 2.0 * x, 0.0], yaw, pitch: -10.0, field_of_view: 45.0 }
+    }
+
+    fn path(interpolation: Interpolation, keyframes: &[Keyframe]) -> CameraPath {
+        let mut path = CameraPath { interpolation, keyframes: Vec::new() };
+        for &keyframe in keyframes {
+            path.add(keyframe);
+        }
+        path
+    }
+
+    fn assert_close(a: Keyframe, b: Keyframe) {
+        for (a, b) in a.values().iter().zip(b.values()) {
+            assert!((a - b).abs() < EPSILON, "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn keyframes_are_sorted_by_time() {
+        let path = path(Interpolation::CatmullRom, &[keyframe(2.0, 2.0, 0.0), keyframe(0.0, 0.0, 0.0), keyframe(1.0, 1.0, 0.0)]);
+        let times = path.keyframes().iter().map(|k| k.time).collect::<Vec<_>>();
+        assert_eq!(times, [0.0, 1.0, 2.0]);
+        let mut path = path;
+        path.add(keyframe(1.0, 5.0, 0.0));
+        assert_eq!(path.keyframes().len(), 3);
+        assert_eq!(path.keyframes()[1].position[0], 5.0);
+        assert_eq!(path.duration(), 2.0);
+    }
+
+    #[test]
+    fn empty_path_has_no_camera() {
+        assert_eq!(CameraPath::default().sample(1.0), None);
+    }
+
+    #[test]
+    fn camera_stays_at_the_ends() {
+        for interpolation in [Interpolation::CatmullRom, Interpolation::Bezier] {
+            let path = path(interpolation, &[keyframe(1.0, 1.0, 10.0), keyframe(3.0, 5.0, 90.0), keyframe(4.0, 0.0, 0.0)]);
+            assert_close(path.sample(0.0).unwrap(), Keyframe { time: 0.0, ..keyframe(1.0, 1.0, 10.0) });
+            assert_close(path.sample(1.0).unwrap(), keyframe(1.0, 1.0, 10.0));
+            assert_close(path.sample(4.0).unwrap(), keyframe(4.0, 0.0, 0.0));
+            assert_close(path.sample(9.0).unwrap(), Keyframe { time: 9.0, ..keyframe(4.0, 0.0, 0.0) });
+        }
+    }
+
+    #[test]
+    fn catmull_rom_passes_through_the_keyframes() {
+        let keyframes = [keyframe(0.0, 0.0, 0.0), keyframe(0.5, 3.0, 45.0), keyframe(2.0, -1.0, 180.0), keyframe(3.0, 2.0, 400.0)];
+        let path = path(Interpolation::CatmullRom, &keyframes);
+        for keyframe in keyframes {
+            assert_close(path.sample(keyframe.time).unwrap(), keyframe);
+        }
+    }
+
+    #[test]
+    fn catmull_rom_keeps_constant_speed() {
+        // keyframes on a straight line at uneven intervals but at the same speed
+        let path = path(Interpolation::CatmullRom, &[keyframe(0.0, 0.0, 0.0), keyframe(1.0, 1.0, 10.0), keyframe(3.0, 3.0, 30.0), keyframe(3.5, 3.5, 35.0)]);
+        for time in [0.25, 0.5, 1.7, 2.9, 3.2] {
+            assert_close(path.sample(time).unwrap(), keyframe(time, time, 10.0 * time));
+        }
+    }
+
+    #[test]
+    fn bezier_follows_the_control_points() {
+        let path = path(Interpolation::Bezier, &[keyframe(0.0, 0.0, 0.0), keyframe(1.0, 4.0, 40.0), keyframe(2.0, 0.0, 0.0)]);
+        // halfway the quadratic curve is at 1/4 + 1/2 * 4 + 1/4 * 0
+        let middle = path.sample(1.0).unwrap();
+        assert!((middle.position[0] - 2.0).abs() < EPSILON);
+        assert!((middle.yaw - 20.0).abs() < EPSILON);
+        // the curve does not pass through the middle control point
+        assert!(middle.position[0] < 4.0);
+    }
+
+    #[test]
+    fn round_trip_through_a_file() {
+        let path = path(Interpolation::Bezier, &[keyframe(0.0, 1.0, 2.0), keyframe(1.5, -3.0, 7.5)]);
+        let file = std::env::temp_dir().join(format!("camera_path_round_trip_{}.ron", std::process::id()));
+        path.save(&file).unwrap();
+        let loaded = CameraPath::load(&file);
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(loaded.unwrap(), path);
+    }
+}
@@ -0,0 +1,190 @@
+// The parts of the scene shaders that do not depend on the material: the vertex shader,
+// the lights and the shadows. This file is put in front of shader.wgsl and pbr.wgsl.
+
+struct Observer {
+    view_proj: mat4x4<f32>,
+    position: vec4<f32>,
+};
+ 
+@group(1) @binding(0)
+var<uniform> observer: Observer;
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) tex_coords: vec2<f32>,
+    @location(2) normal: vec3<f32>,
+    @location(3) tangent: vec3<f32>,
+    @location(4) bitangent: vec3<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+    // the normal direction in the world reference frame
+    @location(1) world_normal: vec3<f32>,
+    // the location of the vertex in the world reference frame
+    @location(2) position: vec3<f32>,
+    // the tangent space of the surface in the world reference frame
+    // needed to turn the normal map samples into world space normals
+    @location(3) world_tangent: vec3<f32>,
+    @location(4) world_bitangent: vec3<f32>,
+};
+
+struct InstanceInput {
+    @location(5) transform_matrix_0: vec4<f32>,
+    @location(6) transform_matrix_1: vec4<f32>,
+    @location(7) transform_matrix_2: vec4<f32>,
+    @location(8) transform_matrix_3: vec4<f32>,
+    // the inverse transpose of the upper 3x3 of the transform, for the normals
+    @location(9) normal_matrix_0: vec4<f32>,
+    @location(10) normal_matrix_1: vec4<f32>,
+    @location(11) normal_matrix_2: vec4<f32>,
+};
+
+struct Light {
+    position: vec3<f32>,
+    light_type: u32,
+    color: vec3<f32>,
+    intensity: f32,
+    direction: vec3<f32>,
+    shadow_layer: u32,
+    attenuation: vec3<f32>,
+    inner_cone_angle: f32,
+    outer_cone_angle: f32,
+}
+
+struct Lights {
+    count: u32,
+    lights: array<Light>,
+}
+
+const LIGHT_POINT: u32 = 0u;
+const LIGHT_DIRECTIONAL: u32 = 1u;
+const LIGHT_SPOT: u32 = 2u;
+
+// The direction towards the light and how much of its intensity arrives at a point
+struct LightContribution {
+    direction: vec3<f32>,
+    strength: f32,
+}
+
+fn light_contribution(light: Light, position: vec3<f32>) -> LightContribution {
+    var out: LightContribution;
+    if light.light_type == LIGHT_DIRECTIONAL {
+        // the sun is so far away that it shines in the same direction everywhere
+        out.direction = normalize(-light.direction);
+        out.strength = light.intensity;
+        return out;
+    }
+
+    let to_light = light.position - position;
+    let light_distance = length(to_light);
+    out.direction = to_light / light_distance;
+    let attenuation = light.attenuation.x
+        + light.attenuation.y * light_distance
+        + light.attenuation.z * light_distance * light_distance;
+    out.strength = light.intensity / max(attenuation, 0.0001);
+
+    if light.light_type == LIGHT_SPOT {
+        // fade out smoothly between the inner and the outer cone
+        let cos_angle = dot(-out.direction, normalize(light.direction));
+        let cos_inner = cos(light.inner_cone_angle);
+        let cos_outer = cos(light.outer_cone_angle);
+        out.strength *= smoothstep(cos_outer, cos_inner, cos_angle);
+    }
+    return out;
+}
+
+// Here the vertex shader is doing pretty boring stuff, it simply maps the points into the view volume
+// via transforming 
+@vertex
+fn vs_main(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> VertexOutput {
+    let instance_transform = mat4x4<f32>(
+        instance.transform_matrix_0,
+        instance.transform_matrix_1,
+        instance.transform_matrix_2,
+        instance.transform_matrix_3,
+    );
+    let normal_matrix = mat3x3<f32>(
+        instance.normal_matrix_0.xyz,
+        instance.normal_matrix_1.xyz,
+        instance.normal_matrix_2.xyz,
+    );
+    var out: VertexOutput;
+    out.tex_coords = model.tex_coords;
+    
+    // translate the 3d vectors for position and normal to homogenious coordinates
+    // also calculate the vectors in the "world coordinate system"
+    // this is needed for calculating the lighting in the fragment shader
+    // the tangents lie in the surface and move with it, the normal has to stay
+    // perpendicular to them, which needs the normal matrix when the scale is uneven
+    out.world_normal = normal_matrix * model.normal;
+    out.world_tangent = (instance_transform * vec4<f32>(model.tangent, 0.0)).xyz;
+    out.world_bitangent = (instance_transform * vec4<f32>(model.bitangent, 0.0)).xyz;
+    var world_position: vec4<f32> = instance_transform * vec4<f32>(model.position, 1.0);
+    out.position = world_position.xyz;
+
+    // this is the thing that really matters to the clipping and rasterization process
+    out.clip_position = observer.view_proj * world_position;
+    return out;
+}
+
+@group(2) @binding(0)
+var<storage, read> lights: Lights;
+@group(3) @binding(0)
+var t_shadow: texture_depth_2d_array;
+@group(3) @binding(1)
+var s_shadow: sampler_comparison;
+@group(3) @binding(2)
+var<storage, read> shadow_matrices: array<mat4x4<f32>>;
+
+const NO_SHADOW: u32 = 0xffffffffu;
+
+// Point lights have six shadow map layers, one per face of a cube around the light, in the
+// order +X, -X, +Y, -Y, +Z, -Z. The face is the one the light looks through to see the point.
+fn shadow_map_layer(light: Light, position: vec3<f32>) -> u32 {
+    if light.light_type != LIGHT_POINT {
+        return light.shadow_layer;
+    }
+    let v = position - light.position;
+    let a = abs(v);
+    if a.x >= a.y && a.x >= a.z {
+        return light.shadow_layer + select(1u, 0u, v.x > 0.0);
+    }
+    if a.y >= a.z {
+        return light.shadow_layer + select(3u, 2u, v.y > 0.0);
+    }
+    return light.shadow_layer + select(5u, 4u, v.z > 0.0);
+}
+
+// How much of the light reaches the point (1.0 fully lit, 0.0 in shadow). The shadow map
+// is sampled 3x3 times around the point and averaged (percentage closer filtering) to
+// soften the edges of the shadows.
+fn shadow_factor(light: Light, position: vec3<f32>) -> f32 {
+    if light.shadow_layer == NO_SHADOW {
+        return 1.0;
+    }
+    let layer = shadow_map_layer(light, position);
+    let light_clip = shadow_matrices[layer] * vec4<f32>(position, 1.0);
+    if light_clip.w <= 0.0 {
+        return 1.0;
+    }
+    let ndc = light_clip.xyz / light_clip.w;
+    // outside of what the light sees, there is no shadow information
+    if any(abs(ndc.xy) > vec2<f32>(1.0)) || ndc.z > 1.0 {
+        return 1.0;
+    }
+    let uv = ndc.xy * vec2<f32>(0.5, -0.5) + 0.5;
+    let texel_size = 1.0 / vec2<f32>(textureDimensions(t_shadow));
+    var lit = 0.0;
+    for (var y = -1; y <= 1; y += 1) {
+        for (var x = -1; x <= 1; x += 1) {
+            let offset = vec2<f32>(f32(x), f32(y)) * texel_size;
+            lit += textureSampleCompareLevel(t_shadow, s_shadow, uv + offset, i32(layer), ndc.z);
+        }
+    }
+    return lit / 9.0;
+}
@@ -0,0 +1,156 @@
+use std::iter;
+use anyhow::{anyhow, Context};
+use crate::renderer::Renderer;
+use crate::{texture, tonemap};
+use crate::wgpu_utils::supported_sample_counts;
+
+/// Renders the scene into an offscreen texture instead of a window surface. This makes it
+/// possible to render in CI or in batch jobs where there is no display (and possibly no GPU).
+pub struct HeadlessRenderer {
+    pub renderer: Renderer,
+    color_texture: wgpu::Texture,
+    width: u32,
+    height: u32,
+}
+
+impl HeadlessRenderer {
+    // Same as the window surface, the shaders expect to write into an Srgb target
+    pub const COLOR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+
+    /// Create a renderer that draws into a `width` x `height` offscreen texture. If no
+    /// hardware adapter is available (or `force_fallback_adapter` is set) the software
+    /// fallback adapter of the platform is used.
+    pub async fn new(width: u32, height: u32, force_fallback_adapter: bool) -> anyhow::Result<Self> {
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+            backends: wgpu::Backends::all(),
+            dx12_shader_compiler: Default::default(),
+        });
+
+        // there is no surface here, so any adapter will do
+        let mut adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::default(),
+                compatible_surface: None,
+                force_fallback_adapter,
+            })
+            .await;
+        if adapter.is_none() && !force_fallback_adapter {
+            log::warn!("no hardware adapter found, trying the fallback adapter");
+            adapter = instance
+                .request_adapter(&wgpu::RequestAdapterOptions {
+                    power_preference: wgpu::PowerPreference::default(),
+                    compatible_surface: None,
+                    force_fallback_adapter: true,
+                })
+                .await;
+        }
+        let adapter = adapter.ok_or_else(|| anyhow!("no suitable adapter found for headless rendering"))?;
+        log::info!("headless rendering on {:?}", adapter.get_info());
+
+        let features = adapter.features() & wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES;
+        let (device, queue) = adapter
+            .request_device(
+                &wgpu::DeviceDescriptor {
+                    label: Some("headless device"),
+                    features,
+                    // software adapters usually don't reach the default limits
+                    limits: wgpu::Limits::downlevel_defaults(),
+                },
+                None, // Trace path
+            )
+            .await?;
+
+        let color_texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("headless color target"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: Self::COLOR_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+
+        let sample_counts = supported_sample_counts(&adapter, features, &[tonemap::ToneMapping::HDR_FORMAT, texture::Texture::DEPTH_FORMAT]);
+        let renderer = Renderer::new(device, queue, Self::COLOR_FORMAT, width, height, sample_counts).await;
+        Ok(Self { renderer, color_texture, width, height })
+    }
+
+    /// Draw the scene and read the result back into main memory
+    pub fn render(&self) -> anyhow::Result<image::RgbaImage> {
+        let device = &self.renderer.device;
+        let view = self.color_texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        // rows of a texture to buffer copy have to be aligned to COPY_BYTES_PER_ROW_ALIGNMENT
+        // so the buffer may be a bit wider than the image
+        let unpadded_bytes_per_row = 4 * self.width;
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let padded_bytes_per_row = unpadded_bytes_per_row.div_ceil(align) * align;
+        let output_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("headless readback buffer"),
+            size: (padded_bytes_per_row * self.height) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Headless Render Encoder"),
+        });
+        self.renderer.render(&mut encoder, &view);
+        encoder.copy_texture_to_buffer(
+            wgpu::ImageCopyTexture {
+                aspect: wgpu::TextureAspect::All,
+                texture: &self.color_texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            wgpu::ImageCopyBuffer {
+                buffer: &output_buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_bytes_per_row),
+                    rows_per_image: Some(self.height),
+                },
+            },
+            wgpu::Extent3d {
+                width: self.width,
+                height: self.height,
+                depth_or_array_layers: 1,
+            },
+        );
+        self.renderer.queue.submit(iter::once(encoder.finish()));
+
+        // wait for the GPU to finish and map the buffer into main memory
+        let buffer_slice = output_buffer.slice(..);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        buffer_slice.map_async(wgpu::MapMode::Read, move |result| {
+            sender.send(result).ok();
+        });
+        device.poll(wgpu::Maintain::Wait);
+        receiver.recv()?.context("failed to map the readback buffer")?;
+
+        let pixels = {
+            let data = buffer_slice.get_mapped_range();
+            data.chunks(padded_bytes_per_row as usize)
+                .flat_map(|row| &row[..unpadded_bytes_per_row as usize])
+                .copied()
+                .collect::<Vec<u8>>()
+        };
+        output_buffer.unmap();
+
+        image::RgbaImage::from_raw(self.width, self.height, pixels)
+            .ok_or_else(|| anyhow!("readback buffer does not match the image size"))
+    }
+
+    /// Draw the scene and write it to `path` as a PNG image
+    pub fn render_to_png<P: AsRef<std::path::Path>>(&self, path: P) -> anyhow::Result<()> {
+        let image = self.render()?;
+        image.save_with_format(path.as_ref(), image::ImageFormat::Png)
+            .with_context(|| format!("failed to write {:?}", path.as_ref()))?;
+        Ok(())
+    }
+}
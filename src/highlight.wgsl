@@ -0,0 +1,36 @@
+// Draws the selected instance once more on top of itself in a translucent color
+
+struct Observer {
+	view_proj: mat4x4<f32>,
+	position: vec4<f32>,
+}
+@group(0) @binding(0)
+var<uniform> observer: Observer;
+
+struct VertexInput {
+	@location(0) position: vec3<f32>,
+};
+
+struct InstanceInput {
+	@location(5) transform_matrix_0: vec4<f32>,
+	@location(6) transform_matrix_1: vec4<f32>,
+	@location(7) transform_matrix_2: vec4<f32>,
+	@location(8) transform_matrix_3: vec4<f32>,
+};
+
+@vertex
+fn vs_main(model: VertexInput, instance: InstanceInput) -> @builtin(position) vec4<f32> {
+	let instance_transform = mat4x4<f32>(
+		instance.transform_matrix_0,
+		instance.transform_matrix_1,
+		instance.transform_matrix_2,
+		instance.transform_matrix_3,
+	);
+	// the same computation as in the scene shaders, so the depth comes out exactly the same
+	return observer.view_proj * (instance_transform * vec4<f32>(model.position, 1.0));
+}
+
+@fragment
+fn fs_main() -> @location(0) vec4<f32> {
+	return vec4<f32>(1.0, 0.5, 0.0, 0.4);
+}
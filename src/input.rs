@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::path::Path;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use winit::event::{ElementState, KeyboardInput, MouseButton, VirtualKeyCode, WindowEvent};
+
+/// The things the user can do with the keyboard and the mouse buttons. Moving the mouse
+/// and the scroll wheel are not actions, they are always handled.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Action {
+    MoveForward,
+    MoveBackward,
+    MoveLeft,
+    MoveRight,
+    MoveUp,
+    MoveDown,
+    /// turn the camera (or orbit around the target) while this is held and the mouse moves
+    Rotate,
+    /// move the target of the orbiting camera while this is held and the mouse moves
+    Pan,
+    /// select the instance under the cursor, if the mouse did not move in between
+    Select,
+    FrameSelection,
+}
+
+/// A key or a mouse button that can trigger an action
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Binding {
+    Key(VirtualKeyCode),
+    Mouse(MouseButton),
+}
+
+/// Which keys and mouse buttons trigger which actions. Several bindings can trigger the
+/// same action and one binding can trigger several actions.
+///
+/// The map is read from a RON file. Actions that are not in the file keep their default
+/// bindings, an empty list unbinds an action:
+///
+/// ```ron
+/// (
+///     bindings: {
+///         MoveForward: [Key(Z), Key(Up)],
+///         MoveLeft: [Key(Q), Key(Left)],
+///         FrameSelection: [],
+///     },
+/// )
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InputMap {
+    bindings: HashMap<Action, Vec<Binding>>,
+}
+
+impl Default for InputMap {
+    /// WASD and the arrow keys to move, space and shift to go up and down, the left mouse
+    /// button to look around and to select, the middle one to pan
+    fn default() -> Self {
+        use Binding::*;
+        let bindings = HashMap::from([
+            (Action::MoveForward, vec![Key(VirtualKeyCode::W), Key(VirtualKeyCode::Up)]),
+            (Action::MoveBackward, vec![Key(VirtualKeyCode::S), Key(VirtualKeyCode::Down)]),
+            (Action::MoveLeft, vec![Key(VirtualKeyCode::A), Key(VirtualKeyCode::Left)]),
+            (Action::MoveRight, vec![Key(VirtualKeyCode::D), Key(VirtualKeyCode::Right)]),
+            (Action::MoveUp, vec![Key(VirtualKeyCode::Space)]),
+            (Action::MoveDown, vec![Key(VirtualKeyCode::LShift)]),
+            (Action::Rotate, vec![Mouse(MouseButton::Left)]),
+            (Action::Pan, vec![Mouse(MouseButton::Middle)]),
+            (Action::Select, vec![Mouse(MouseButton::Left)]),
+            (Action::FrameSelection, vec![Key(VirtualKeyCode::F)]),
+        ]);
+        Self { bindings }
+    }
+}
+
+impl InputMap {
+    pub fn from_ron(text: &str) -> anyhow::Result<Self> {
+        let mut map: Self = ron::from_str(text)?;
+        for (action, bindings) in Self::default().bindings {
+            map.bindings.entry(action).or_insert(bindings);
+        }
+        Ok(map)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).with_context(|| format!("failed to read {:?}", path))?;
+        Self::from_ron(&text).with_context(|| format!("failed to parse the input config {:?}", path))
+    }
+
+    /// The map from the file if there is one, the defaults otherwise. A broken file is
+    /// reported and the defaults are used instead.
+    pub fn load_or_default<P: AsRef<Path>>(path: P) -> Self {
+        if !path.as_ref().exists() {
+            return Self::default();
+        }
+        Self::load(path).unwrap_or_else(|e| {
+            log::error!("{:?}", e);
+            Self::default()
+        })
+    }
+
+    /// The actions a key or mouse button triggers
+    pub fn actions_for(&self, binding: Binding) -> impl Iterator<Item = Action> + '_ {
+        self.bindings
+            .iter()
+            .filter(move |(_, bindings)| bindings.contains(&binding))
+            .map(|(&action, _)| action)
+    }
+
+    /// The actions that start (true) or stop (false) with a window event
+    pub fn actions(&self, event: &WindowEvent) -> Vec<(Action, bool)> {
+        let (binding, state) = match event {
+            WindowEvent::KeyboardInput { input: KeyboardInput { state, virtual_keycode: Some(key), .. }, .. } => {
+                (Binding::Key(*key), state)
+            }
+            WindowEvent::MouseInput { state, button, .. } => (Binding::Mouse(*button), state),
+            _ => return Vec::new(),
+        };
+        let pressed = *state == ElementState::Pressed;
+        self.actions_for(binding).map(|action| (action, pressed)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(actions: impl Iterator<Item = Action>) -> Vec<Action> {
+        let mut actions = actions.collect::<Vec<_>>();
+        actions.sort_by_key(|action| format!("{:?}", action));
+        actions
+    }
+
+    #[test]
+    fn defaults_are_wasd() {
+        let map = InputMap::default();
+        assert_eq!(sorted(map.actions_for(Binding::Key(VirtualKeyCode::W))), [Action::MoveForward]);
+        assert_eq!(sorted(map.actions_for(Binding::Key(VirtualKeyCode::A))), [Action::MoveLeft]);
+        assert_eq!(sorted(map.actions_for(Binding::Key(VirtualKeyCode::S))), [Action::MoveBackward]);
+        assert_eq!(sorted(map.actions_for(Binding::Key(VirtualKeyCode::D))), [Action::MoveRight]);
+        assert_eq!(sorted(map.actions_for(Binding::Mouse(MouseButton::Left))), [Action::Rotate, Action::Select]);
+        assert_eq!(map.actions_for(Binding::Key(VirtualKeyCode::R)).count(), 0);
+    }
+
+    #[test]
+    fn config_overrides_some_actions() {
+        let map = InputMap::from_ron(r#"(
+            bindings: {
+                MoveForward: [Key(R), Key(Up)],
+                MoveBackward: [Key(H)],
+                FrameSelection: [],
+                Pan: [Mouse(Right), Mouse(Other(4))],
+            },
+        )"#).unwrap();
+        assert_eq!(sorted(map.actions_for(Binding::Key(VirtualKeyCode::R))), [Action::MoveForward]);
+        assert_eq!(sorted(map.actions_for(Binding::Key(VirtualKeyCode::Up))), [Action::MoveForward]);
+        assert_eq!(map.actions_for(Binding::Key(VirtualKeyCode::W)).count(), 0);
+        assert_eq!(map.actions_for(Binding::Key(VirtualKeyCode::F)).count(), 0);
+        assert_eq!(sorted(map.actions_for(Binding::Mouse(MouseButton::Other(4)))), [Action::Pan]);
+        // the actions that are not in the file keep their defaults
+        assert_eq!(sorted(map.actions_for(Binding::Key(VirtualKeyCode::A))), [Action::MoveLeft]);
+        assert_eq!(sorted(map.actions_for(Binding::Mouse(MouseButton::Middle))), []);
+    }
+
+    #[test]
+    fn broken_config_is_an_error() {
+        assert!(InputMap::from_ron("(bindings: { Jump: [Key(Space)] })").is_err());
+        assert!(InputMap::from_ron("(bindings: { MoveUp: [Key(NotAKey)] })").is_err());
+    }
+
+    #[test]
+    fn round_trip_through_ron() {
+        let map = InputMap::default();
+        let text = ron::to_string(&map).unwrap();
+        assert_eq!(InputMap::from_ron(&text).unwrap(), map);
+    }
+}
@@ -0,0 +1,328 @@
+use std::f32::consts::PI;
+use std::path::Path;
+use anyhow::Context;
+use cgmath::Vector3;
+
+// the angle between neighbouring points of the spiral and the sphere, it spreads the
+// points evenly without lining them up
+const GOLDEN_ANGLE: f32 = PI * (3.0 - 2.236068);
+
+/// The most instances a layout can be edited to or read from a file
+pub const MAX_INSTANCES: u32 = 1_000_000;
+
+/// Where the animated instances are placed
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Layout {
+    /// Columns along x, rows along z and layers along y
+    Grid { size: [u32; 3], spacing: f32 },
+    /// A flat spiral (phyllotaxis) in the xz plane, the points are evenly spread over a disc
+    Spiral { count: u32, spacing: f32 },
+    /// Evenly spread over the surface of a sphere around the origin
+    SphereShell { count: u32, radius: f32 },
+    /// Scattered in a box around the origin, the same seed scatters them the same way
+    Random { count: u32, extent: f32, seed: u64 },
+    /// The positions from a CSV file with one x,y,z per line
+    Csv {
+        file: String,
+        // read from the file, so they are not written to scene files
+        #[serde(skip)]
+        positions: Vec<Vector3<f32>>,
+    },
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout::Grid { size: [10, 1, 10], spacing: 2.0 }
+    }
+}
+
+impl Layout {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Layout::Grid { .. } => "grid",
+            Layout::Spiral { .. } => "spiral",
+            Layout::SphereShell { .. } => "sphere shell",
+            Layout::Random { .. } => "random",
+            Layout::Csv { .. } => "CSV file",
+        }
+    }
+
+    /// A layout of this kind with about the same number of instances
+    fn with_count(&self, kind: &str) -> Self {
+        let count = self.positions().len().max(1) as u32;
+        match kind {
+            "grid" => {
+                let side = (count as f32).sqrt().ceil() as u32;
+                Layout::Grid { size: [side, 1, side], spacing: 2.0 }
+            }
+            "spiral" => Layout::Spiral { count, spacing: 2.0 },
+            "sphere shell" => Layout::SphereShell { count, radius: 2.0 * (count as f32).sqrt() },
+            "random" => Layout::Random { count, extent: 2.0 * (count as f32).cbrt(), seed: 1 },
+            _ => Layout::Csv { file: "instances.csv".to_string(), positions: Vec::new() },
+        }
+    }
+
+    /// The number of instances the layout places, without placing them. The size of a grid
+    /// is multiplied as u64 and saturates, so that it cannot overflow.
+    pub fn count(&self) -> u64 {
+        match self {
+            Layout::Grid { size, .. } => size.iter().fold(1u64, |count, &n| count.saturating_mul(n as u64)),
+            Layout::Spiral { count, .. } | Layout::SphereShell { count, .. } | Layout::Random { count, .. } => *count as u64,
+            Layout::Csv { positions, .. } => positions.len() as u64,
+        }
+    }
+
+    /// Fails if the layout places more than `MAX_INSTANCES` instances, the UI cannot get
+    /// there but a scene file can
+    pub fn check(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.count() <= MAX_INSTANCES as u64,
+            "the {} layout places {} instances, more than the {} that are supported",
+            self.name(),
+            self.count(),
+            MAX_INSTANCES,
+        );
+        Ok(())
+    }
+
+    /// The position of every instance, but never more than `MAX_INSTANCES` of them
+    pub fn positions(&self) -> Vec<Vector3<f32>> {
+        match self {
+            Layout::Grid { size: [columns, layers, rows], spacing } => {
+                // the same centering for all three axes, a single layer stays at y = 0
+                let offset = |i: u32, n: u32| spacing * (i as f32 - (n / 2) as f32);
+                let count = self.count().min(MAX_INSTANCES as u64) as usize;
+                let mut positions = Vec::with_capacity(count);
+                'grid: for layer in 0..*layers {
+                    for row in 0..*rows {
+                        for column in 0..*columns {
+                            if positions.len() == count {
+                                break 'grid;
+                            }
+                            positions.push(Vector3::new(offset(column, *columns), offset(layer, *layers), offset(row, *rows)));
+                        }
+                    }
+                }
+                positions
+            }
+            Layout::Spiral { count, spacing } => (0..(*count).min(MAX_INSTANCES)).map(|i| {
+                let (sin, cos) = (i as f32 * GOLDEN_ANGLE).sin_cos();
+                let radius = spacing * (i as f32).sqrt();
+                Vector3::new(radius * cos, 0.0, radius * sin)
+            }).collect(),
+            Layout::SphereShell { count, radius } => {
+                let count = (*count).min(MAX_INSTANCES);
+                (0..count).map(|i| {
+                    // evenly spaced heights, every point turned on by the golden angle
+                    let y = 1.0 - 2.0 * (i as f32 + 0.5) / count as f32;
+                    let ring = (1.0 - y * y).sqrt();
+                    let (sin, cos) = (i as f32 * GOLDEN_ANGLE).sin_cos();
+                    Vector3::new(ring * cos, y, ring * sin) * *radius
+                }).collect()
+            }
+            Layout::Random { count, extent, seed } => {
+                let mut random = SplitMix64(*seed);
+                (0..(*count).min(MAX_INSTANCES)).map(|_| {
+                    let mut coordinate = || (random.next_f32() * 2.0 - 1.0) * extent;
+                    Vector3::new(coordinate(), coordinate(), coordinate())
+                }).collect()
+            }
+            Layout::Csv { positions, .. } => positions.clone(),
+        }
+    }
+
+    /// Edit the kind and the parameters of the layout, returns whether they changed
+    pub fn ui(&mut self, ui: &mut egui::Ui) -> bool {
+        let mut changed = false;
+        let mut kind = self.name();
+        egui::ComboBox::from_label("layout")
+            .selected_text(kind)
+            .show_ui(ui, |ui| {
+                for name in ["grid", "spiral", "sphere shell", "random", "CSV file"] {
+                    ui.selectable_value(&mut kind, name, name);
+                }
+            });
+        if kind != self.name() {
+            *self = self.with_count(kind);
+            changed = true;
+        }
+        match self {
+            Layout::Grid { size, spacing } => {
+                ui.horizontal(|ui| {
+                    for (axis, name) in ["x", "y", "z"].iter().enumerate() {
+                        // every axis can only grow as far as the other two leave room for
+                        let others = (0..3).filter(|&other| other != axis).map(|other| size[other]).product::<u32>();
+                        let range = 1..=(MAX_INSTANCES / others).max(1);
+                        changed |= ui.add(egui::DragValue::new(&mut size[axis]).clamp_range(range).prefix(format!("{} ", name))).changed();
+                    }
+                });
+                changed |= ui.add(egui::Slider::new(spacing, 1.0..=10.0).text("spacing")).changed();
+            }
+            Layout::Spiral { count, spacing } => {
+                changed |= count_ui(ui, count);
+                changed |= ui.add(egui::Slider::new(spacing, 1.0..=10.0).text("spacing")).changed();
+            }
+            Layout::SphereShell { count, radius } => {
+                changed |= count_ui(ui, count);
+                changed |= ui.add(egui::Slider::new(radius, 1.0..=1000.0).logarithmic(true).text("radius")).changed();
+            }
+            Layout::Random { count, extent, seed } => {
+                changed |= count_ui(ui, count);
+                changed |= ui.add(egui::Slider::new(extent, 1.0..=1000.0).logarithmic(true).text("extent")).changed();
+                changed |= ui.add(egui::DragValue::new(seed).prefix("seed ")).changed();
+            }
+            Layout::Csv { file, positions } => {
+                ui.horizontal(|ui| {
+                    ui.text_edit_singleline(file);
+                    if ui.button("load").clicked() {
+                        match load_csv(&*file) {
+                            Ok(loaded) => {
+                                *positions = loaded;
+                                changed = true;
+                            }
+                            Err(e) => log::error!("{:?}", e),
+                        }
+                    }
+                });
+                ui.label(format!("{} positions", positions.len()));
+            }
+        }
+        changed
+    }
+}
+
+fn count_ui(ui: &mut egui::Ui, count: &mut u32) -> bool {
+    ui.add(egui::Slider::new(count, 1..=MAX_INSTANCES).logarithmic(true).text("count")).changed()
+}
+
+/// Read positions from CSV text, one x,y,z per line. Empty lines, lines starting with #
+/// and a header line that is not made of numbers are skipped. There can be at most
+/// `MAX_INSTANCES` positions.
+pub fn parse_csv(text: &str) -> anyhow::Result<Vec<Vector3<f32>>> {
+    let mut positions = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields = line.split(',').map(|field| field.trim().parse::<f32>()).collect::<Result<Vec<_>, _>>();
+        match fields.as_deref() {
+            Ok([x, y, z]) => {
+                anyhow::ensure!(positions.len() < MAX_INSTANCES as usize, "more than {} positions", MAX_INSTANCES);
+                positions.push(Vector3::new(*x, *y, *z))
+            }
+            Err(_) if number == 0 => continue,
+            _ => anyhow::bail!("line {} is not x,y,z: {:?}", number + 1, line),
+        }
+    }
+    Ok(positions)
+}
+
+pub fn load_csv<P: AsRef<Path>>(path: P) -> anyhow::Result<Vec<Vector3<f32>>> {
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(path).with_context(|| format!("failed to read {:?}", path))?;
+    parse_csv(&text).with_context(|| format!("failed to parse the positions in {:?}", path))
+}
+
+// a small random number generator, good enough for scattering and always the same for the
+// same seed
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// uniform in [0, 1)
+    fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::InnerSpace;
+
+    const EPSILON: f32 = 1e-4;
+
+    #[test]
+    fn grid_has_every_dimension() {
+        let positions = Layout::Grid { size: [3, 2, 4], spacing: 2.0 }.positions();
+        assert_eq!(positions.len(), 24);
+        assert_eq!(positions[0], Vector3::new(-2.0, -2.0, -4.0));
+        assert_eq!(positions[1], Vector3::new(0.0, -2.0, -4.0));
+        assert_eq!(positions[3], Vector3::new(-2.0, -2.0, -2.0));
+        assert_eq!(positions[23], Vector3::new(2.0, 0.0, 2.0));
+    }
+
+    #[test]
+    fn default_grid_is_flat() {
+        let positions = Layout::default().positions();
+        assert_eq!(positions.len(), 100);
+        assert!(positions.iter().all(|p| p.y == 0.0));
+        assert_eq!(positions[0], Vector3::new(-10.0, 0.0, -10.0));
+        assert_eq!(positions[99], Vector3::new(8.0, 0.0, 8.0));
+    }
+
+    #[test]
+    fn spiral_grows_outwards() {
+        let positions = Layout::Spiral { count: 50, spacing: 1.5 }.positions();
+        assert_eq!(positions.len(), 50);
+        assert!(positions.iter().all(|p| p.y == 0.0));
+        assert!(positions.windows(2).all(|pair| pair[0].magnitude() < pair[1].magnitude()));
+        assert!((positions[49].magnitude() - 1.5 * 7.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn sphere_shell_is_on_the_sphere() {
+        let positions = Layout::SphereShell { count: 200, radius: 5.0 }.positions();
+        assert_eq!(positions.len(), 200);
+        assert!(positions.iter().all(|p| (p.magnitude() - 5.0).abs() < EPSILON));
+        // evenly spread, so the center of all of them is close to the center of the sphere
+        let center = positions.iter().fold(Vector3::new(0.0, 0.0, 0.0), |sum, p| sum + p) / 200.0;
+        assert!(center.magnitude() < 0.1, "{:?}", center);
+    }
+
+    #[test]
+    fn random_depends_only_on_the_seed() {
+        let layout = Layout::Random { count: 100, extent: 3.0, seed: 7 };
+        let positions = layout.positions();
+        assert_eq!(positions, layout.positions());
+        assert!(positions.iter().all(|p| [p.x, p.y, p.z].iter().all(|c| c.abs() <= 3.0)));
+        assert_ne!(positions, Layout::Random { count: 100, extent: 3.0, seed: 8 }.positions());
+    }
+
+    #[test]
+    fn layouts_have_a_limit() {
+        let grid = Layout::Grid { size: [100_000, 1, 100_000], spacing: 1.0 };
+        assert_eq!(grid.count(), 10_000_000_000);
+        assert!(grid.check().is_err());
+        assert_eq!(grid.positions().len(), MAX_INSTANCES as usize);
+        // a product that does not even fit into a u64
+        assert_eq!(Layout::Grid { size: [u32::MAX; 3], spacing: 1.0 }.count(), u64::MAX);
+        let spiral = Layout::Spiral { count: MAX_INSTANCES + 1, spacing: 1.0 };
+        assert!(spiral.check().is_err());
+        assert_eq!(spiral.positions().len(), MAX_INSTANCES as usize);
+        assert!(Layout::Random { count: MAX_INSTANCES, extent: 1.0, seed: 1 }.check().is_ok());
+    }
+
+    #[test]
+    fn csv_positions() {
+        let text = "x, y, z\n1, 2, 3\n\n# a comment\n-0.5,0,1e2\n";
+        assert_eq!(parse_csv(text).unwrap(), [Vector3::new(1.0, 2.0, 3.0), Vector3::new(-0.5, 0.0, 100.0)]);
+        assert!(parse_csv("1, 2, 3\n1, 2\n").is_err());
+        assert!(parse_csv("1, 2, 3\nx, y, z\n").is_err());
+    }
+
+    #[test]
+    fn csv_has_a_limit() {
+        let line = "1, 2, 3\n";
+        assert_eq!(parse_csv(&line.repeat(MAX_INSTANCES as usize)).unwrap().len(), MAX_INSTANCES as usize);
+        assert!(parse_csv(&line.repeat(MAX_INSTANCES as usize + 1)).is_err());
+    }
+}
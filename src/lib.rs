@@ -1,73 +1,69 @@
 use std::iter;
+use std::path::{Path, PathBuf};
 use instant::Instant;
-use cgmath::*;
-use observer::Camera;
-use wgpu::util::DeviceExt;
 use winit::{
     event::*,
     event_loop::{ControlFlow, EventLoop},
     window::{Window, WindowBuilder},
 };
 use egui_winit_platform::{Platform, PlatformDescriptor};
-use model::{GPUVertex, DrawModel, Instance, GPUInstance};
 use egui::FontDefinitions;
-use crate::wgpu_utils::create_render_pipeline;
-use std::iter::zip;
+use renderer::{Renderer, Selection};
+use headless::HeadlessRenderer;
+use scene_file::SceneDescription;
+use observer::{CameraMode, DepthMode, ProjectionKind, ViewPreset};
+use input::{Action, InputMap};
+use wgpu_utils::supported_sample_counts;
 
 #[cfg(target_arch="wasm32")]
 use wasm_bindgen::prelude::*;
 
-/// A custom event type for the winit app.
-enum REvent {
-    RequestRedraw,
-}
-
-/// This is the repaint signal type that egui needs for requesting a repaint from another thread.
-/// It sends the custom RequestRedraw event to the winit event loop.
-struct ExampleRepaintSignal(std::sync::Mutex<winit::event_loop::EventLoopProxy<REvent>>);
+// the key bindings are read from this file if it exists
+const INPUT_CONFIG: &str = "input.ron";
 
-impl epi::backend::RepaintSignal for ExampleRepaintSignal {
-    fn request_repaint(&self) {
-        self.0.lock().unwrap().send_event(REvent::RequestRedraw).ok();
-    }
-}
+// the lights are read from storage buffers, also in the vertex shader, and the instances
+// are placed by a compute shader, WebGL2 has neither
+#[cfg(target_arch = "wasm32")]
+compile_error!("the renderer needs storage buffers and compute shaders, which WebGL2 does not support");
 
 mod wgpu_utils;
 mod resources;
 mod model;
+mod animation;
+mod bounds;
+mod camera_path;
 mod texture;
 mod observer;
 mod light;
-
-const NUM_INSTANCES_PER_ROW: u32 = 10;
+mod input;
+mod layout;
+mod shadow;
+mod renderer;
+mod scene;
+mod scene_file;
+mod headless;
+mod tonemap;
 
 struct State {
     surface: wgpu::Surface,
-    device: wgpu::Device,
-    queue: wgpu::Queue,
     config: wgpu::SurfaceConfiguration,
     size: winit::dpi::PhysicalSize<u32>,
-    render_pipeline: wgpu::RenderPipeline,
-    light_render_pipeline: wgpu::RenderPipeline,
     #[allow(dead_code)]
     window: Window,
-    observer: observer::Camera, 
-    mouse_pressed: bool,
-    instances: Vec<Instance>,
-    instance_buffer: wgpu::Buffer,
-    instance_rot_speed: f32,
-    obj_model: model::Object,
-    depth_texture: texture::Texture,
-    light: light::Light,
+    input_map: InputMap,
+    cursor_position: winit::dpi::PhysicalPosition<f64>,
+    // where the left button went down, a click that does not drag selects an instance
+    press_position: Option<winit::dpi::PhysicalPosition<f64>>,
+    renderer: Renderer,
     ui_platform: Platform,
     ui_render_pass: egui_wgpu_backend::RenderPass,
     start_time: Instant,
-    spacing: f32,
+    // where the "save scene" button writes the scene to
+    scene_path: PathBuf,
 }
 
 impl State {
-    async fn new(window: Window) -> Self {
-        let spacing: f32 = 2.;
+    async fn new(window: Window, scene_path: Option<PathBuf>) -> Self {
         let size = window.inner_size();
 
         // The instance is a handle to our GPU
@@ -91,18 +87,14 @@ impl State {
             .await
             .unwrap();
 
-        let (mut device, queue) = adapter
+        // lets us use all the MSAA sample counts the adapter supports and not just 1 and 4
+        let features = adapter.features() & wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES;
+        let (device, queue) = adapter
             .request_device(
                 &wgpu::DeviceDescriptor {
                     label: None,
-                    features: wgpu::Features::empty(),
-                    // WebGL doesn't support all of wgpu's features, so if
-                    // we're building for the web we'll have to disable some.
-                    limits: if cfg!(target_arch = "wasm32") {
-                        wgpu::Limits::downlevel_webgl2_defaults()
-                    } else {
-                        wgpu::Limits::default()
-                    },
+                    features,
+                    limits: wgpu::Limits::default(),
                 },
                 None, // Trace path
             )
@@ -116,8 +108,7 @@ impl State {
         // Srgb surfaces, you'll need to account for that when drawing to the frame.
         let surface_format = surface_caps.formats.iter()
             .copied()
-            .filter(|f| f.is_srgb())
-            .next()
+            .find(|f| f.is_srgb())
             .unwrap_or(surface_caps.formats[0]);
         let config = wgpu::SurfaceConfiguration {
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
@@ -132,159 +123,38 @@ impl State {
 
         // initialize the egui platform
          let platform = Platform::new(PlatformDescriptor {
-            physical_width: size.width as u32,
-            physical_height: size.height as u32,
+            physical_width: size.width,
+            physical_height: size.height,
             scale_factor: window.scale_factor(),
             font_definitions: FontDefinitions::default(),
             style: Default::default(),
         });
         let egui_render_pass = egui_wgpu_backend::RenderPass::new(&device, surface_format, 1);
 
-        let texture_bind_group_layout =
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                entries: &[
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 0,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Texture {
-                            multisampled: false,
-                            view_dimension: wgpu::TextureViewDimension::D2,
-                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                        },
-                        count: None,
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 1,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                        count: None,
-                    },
-                ],
-                label: Some("texture_bind_group_layout"),
-            });
-
-
-        // all the stuff that is needed to initialize the observer of the scene
-        let observer = Camera::new(
-            (0.0, 5.0, 10.0),
-            cgmath::Deg(-90.0),
-            cgmath::Deg(-20.0),
-            size.width,
-            size.height,
-            0.1,
-            100.0,
-            cgmath::Deg(45.0),
-            &device,
-            4.0, 0.4,
-            &queue
-        );
-
-        let light = light::Light::new(&mut device);
-        
-        let depth_texture = texture::Texture::create_depth_texture(&device, &config, "depth texture");
-
-        let render_pipeline_layout =
-            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: Some("Render Pipeline Layout"),
-                bind_group_layouts: &[
-                    &texture_bind_group_layout,
-                    &observer.uniform.bind_group_layout,
-                    &light.bind_group_layout,
-                ],
-                push_constant_ranges: &[],
-            });
-
-        let render_pipeline = {
-            let shader = wgpu::ShaderModuleDescriptor {
-                label: Some("Normal Shader"),
-                source: wgpu::ShaderSource::Wgsl(include_str!("shader.wgsl").into()),
-            };
-            create_render_pipeline(
-                &device,
-                &render_pipeline_layout,
-                config.format,
-                Some(texture::Texture::DEPTH_FORMAT),
-                &[model::ModelVertex::desc(), GPUInstance::desc()],
-                shader,
-            )
-        };
-
-        let light_render_pipeline = {
-            let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: Some("Light Render Pipeline"),
-                bind_group_layouts: &[&observer.uniform.bind_group_layout, &light.bind_group_layout],
-                push_constant_ranges: &[],
-            });
-            let shader = wgpu::ShaderModuleDescriptor {
-                label: Some("Light Shader"),
-                source: wgpu::ShaderSource::Wgsl(include_str!("light.wgsl").into()),
-            };
-            create_render_pipeline(
-                &device,
-                &layout,
-                config.format,
-                Some(texture::Texture::DEPTH_FORMAT),
-                &[model::ModelVertex::desc()],
-                shader)
-        };
-
-        // here we load the model and that we are going to render in this case it is a cube
-        let obj_model = resources::load_model("cube.obj", &device, &queue, &texture_bind_group_layout)
-            .await
-            .unwrap();
-        
-        // this is effectively a somewhat fancy way of generating a list of instances
-        // using generators and iterator mechanics
-        let instances = (0..NUM_INSTANCES_PER_ROW).flat_map(|z| {
-            (0..NUM_INSTANCES_PER_ROW).map(move |x| {
-                let x = spacing * (x as f32 - NUM_INSTANCES_PER_ROW as f32 / 2.0);
-                let z = spacing * (z as f32 - NUM_INSTANCES_PER_ROW as f32 / 2.0);
-                let position = cgmath::Vector3 { x, y: 0.0, z };
-
-                let rotation = if position.is_zero() {
-                    // this is needed so an object at (0, 0, 0) won't get scaled to zero
-                    // as Quaternions can effect scale if they're not created correctly
-                    cgmath::Quaternion::from_axis_angle(cgmath::Vector3::unit_z(), cgmath::Deg(0.0))
-                } else {
-                    cgmath::Quaternion::from_axis_angle(position.normalize(), cgmath::Deg(45.0))
-                };
-
-                Instance {
-                    position, rotation, scale: [1.0, 1.0, 1.0].into()
-                }
-            })
-        }).collect::<Vec<_>>();
-        let instance_data = instances.iter().map(Instance::to_shader_format).collect::<Vec<_>>();
-        let instance_buffer = device.create_buffer_init(
-            &wgpu::util::BufferInitDescriptor {
-                label: Some("Instance Buffer"),
-                contents: bytemuck::cast_slice(&instance_data),
-                usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+        // everything that is needed to draw the scene itself lives in the renderer
+        let sample_counts = supported_sample_counts(&adapter, features, &[tonemap::ToneMapping::HDR_FORMAT, texture::Texture::DEPTH_FORMAT]);
+        let mut renderer = Renderer::new(device, queue, config.format, size.width, size.height, sample_counts).await;
+        if let Some(path) = &scene_path {
+            // a broken scene file leaves the default scene in place
+            if let Err(e) = load_scene(&mut renderer, path).await {
+                log::error!("{:?}", e);
             }
-        );
+        }
 
         let start_time = Instant::now();
         Self {
-            depth_texture,
             surface,
-            device,
-            queue,
             config,
             size,
-            render_pipeline,
-            light_render_pipeline,
-            obj_model,
             window,
-            observer,
-            mouse_pressed: false,
-            instances,
-            instance_buffer,
-            instance_rot_speed: 1.,
-            light,
+            input_map: InputMap::load_or_default(INPUT_CONFIG),
+            cursor_position: winit::dpi::PhysicalPosition::new(0.0, 0.0),
+            press_position: None,
+            renderer,
             ui_platform: platform,
             ui_render_pass: egui_render_pass,
             start_time,
-            spacing,
+            scene_path: scene_path.unwrap_or_else(|| PathBuf::from("scene.ron")),
         }
     }
 
@@ -297,58 +167,44 @@ impl State {
             self.size = new_size;
             self.config.width = new_size.width;
             self.config.height = new_size.height;
-            self.surface.configure(&self.device, &self.config);
-            self.observer.projection.resize(new_size.width, new_size.height);
-            self.depth_texture = texture::Texture::create_depth_texture(&self.device, &self.config, "depth texture")
+            self.surface.configure(&self.renderer.device, &self.config);
+            self.renderer.resize(new_size.width, new_size.height);
         }
     }
 
-    #[allow(unused_variables)]
     fn input(&mut self, event: &Event<()>) -> bool {
-        let event_processed = self.observer.controlls.process_event(event, self.mouse_pressed, self.window().id());
-        if !event_processed {
-            match event {
-                Event::WindowEvent {event: WindowEvent::MouseInput { state, button: MouseButton::Left, .. }, ..} => {
-                    self.mouse_pressed = *state == ElementState::Pressed;
-                    true
+        let mut handled = false;
+        if let Event::WindowEvent { window_id, event } = event {
+            if *window_id != self.window().id() {
+                return false;
+            }
+            if let WindowEvent::CursorMoved { position, .. } = event {
+                self.cursor_position = *position;
+                handled = true;
+            }
+            for (action, pressed) in self.input_map.actions(event) {
+                match action {
+                    Action::Select if pressed => self.press_position = Some(self.cursor_position),
+                    Action::Select => {
+                        if let Some(press) = self.press_position.take() {
+                            let (dx, dy) = (self.cursor_position.x - press.x, self.cursor_position.y - press.y);
+                            if dx * dx + dy * dy < 9.0 {
+                                self.renderer.select_at(self.cursor_position.x as f32, self.cursor_position.y as f32);
+                            }
+                        }
+                    }
+                    Action::FrameSelection if pressed => self.renderer.frame_selection(),
+                    _ => continue,
                 }
-                _ => false,
+                handled = true;
             }
-        } else {
-            true
         }
-    }
-
-    fn update_instances(&mut self, dt: instant::Duration) {
-        let spacing = self.spacing;
-        let positions = (0..NUM_INSTANCES_PER_ROW).flat_map(|z| {
-            (0..NUM_INSTANCES_PER_ROW).map(move |x| {
-                let x = spacing * (x as f32 - NUM_INSTANCES_PER_ROW as f32 / 2.0);
-                let z = spacing * (z as f32 - NUM_INSTANCES_PER_ROW as f32 / 2.0);
-                cgmath::Vector3 { x, y: 0.0, z }
-            })
-        }).collect::<Vec<_>>();
-        self.instances = zip(&self.instances, positions).map(|(inst, pos)| {
-            let new_rot = inst.rotation * cgmath::Quaternion::from_axis_angle(cgmath::Vector3::unit_z(), cgmath::Deg(10.0 * dt.as_secs_f32() * self.instance_rot_speed));
-            Instance {
-                position: pos,
-                rotation: new_rot,
-                scale: [1.0, 1.0, 1.0].into(),
-            }
-        }).collect::<Vec<_>>();
+        let window_id = self.window().id();
+        self.renderer.observer.controlls.process_event(event, &self.input_map, window_id) || handled
     }
 
     fn update(&mut self, dt: instant::Duration) {
-        self.observer.update(dt, &self.queue);
-
-        // update the instances to rotate
-        self.update_instances(dt);
-        let instance_data = self.instances.iter().map(Instance::to_shader_format).collect::<Vec<_>>();
-        // write the rotations to the buffer
-        self.queue.write_buffer(&self.instance_buffer, 0, bytemuck::cast_slice(&instance_data));
-
-        let old_position: cgmath::Vector3<_> = self.light.uniform.position.into();
-        self.light.update(Some((cgmath::Quaternion::from_axis_angle((0.0, 1.0, 0.0).into(), cgmath::Deg(1.0)) * old_position).into()), None, &self.queue);
+        self.renderer.update(dt);
     }
 
 
@@ -371,53 +227,13 @@ impl State {
             .create_view(&wgpu::TextureViewDescriptor::default());
 
         let mut encoder = self
+            .renderer
             .device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor {
                 label: Some("Render Encoder"),
             });
 
-        {
-            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: Some("Render Pass"),
-                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: &view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 0.001,
-                            g: 0.001,
-                            b: 0.001,
-                            a: 1.0,
-                        }),
-                        store: true,
-                    },
-                })],
-                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment { 
-                    view: &self.depth_texture.view,
-                    depth_ops: Some(wgpu::Operations { 
-                        load: wgpu::LoadOp::Clear(1.0),
-                        store: true }),
-                        stencil_ops: None,
-                }),
-            });
-            render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
-            
-            use crate::model::DrawLight;
-            render_pass.set_pipeline(&self.light_render_pipeline);
-            render_pass.draw_light_model(
-                &self.obj_model,
-                &self.observer.uniform.bind_group,
-                &self.light.bind_group
-            );
-
-            render_pass.set_pipeline(&self.render_pipeline);
-            render_pass.draw_model_instanced(
-                &self.obj_model,
-                0..self.instances.len() as u32,
-                &self.observer.uniform.bind_group,
-                &self.light.bind_group
-            );
-        }
+        self.renderer.render(&mut encoder, &view);
 
         // Render The UI
         self.ui_platform.update_time(self.start_time.elapsed().as_secs_f64());
@@ -435,7 +251,102 @@ impl State {
             .show(&self.ui_platform.context(), |ui| {
                 ui.label("This is a label");
                 ui.hyperlink("https://github.com/emilk/egui");
-                ui.add(egui::Slider::new(&mut self.spacing, 2.0..=10.).text("spacing"));
+                if let Some(animation) = &mut self.renderer.animation {
+                    animation.ui(ui);
+                }
+                let mut sample_count = self.renderer.sample_count();
+                egui::ComboBox::from_label("MSAA")
+                    .selected_text(format!("{}x", sample_count))
+                    .show_ui(ui, |ui| {
+                        for &count in self.renderer.supported_sample_counts() {
+                            ui.selectable_value(&mut sample_count, count, format!("{}x", count));
+                        }
+                    });
+                if let Err(e) = self.renderer.set_sample_count(sample_count) {
+                    log::error!("{}", e);
+                }
+                // the samplers are made together with the textures, so a change only shows
+                // on the models of the next scene that is loaded
+                let anisotropy_text = |anisotropy: Option<u16>| anisotropy.map_or("off".to_string(), |samples| format!("{}x", samples));
+                egui::ComboBox::from_label("anisotropic filtering")
+                    .selected_text(anisotropy_text(self.renderer.texture_anisotropy))
+                    .show_ui(ui, |ui| {
+                        for anisotropy in [None, Some(2), Some(4), Some(8), Some(16)] {
+                            ui.selectable_value(&mut self.renderer.texture_anisotropy, anisotropy, anisotropy_text(anisotropy));
+                        }
+                    });
+                let mut reverse_z = self.renderer.depth_mode() == DepthMode::ReverseInfinite;
+                ui.checkbox(&mut reverse_z, "reverse-Z depth, no far plane");
+                self.renderer.set_depth_mode(if reverse_z { DepthMode::ReverseInfinite } else { DepthMode::Standard });
+                self.renderer.tone_mapping.ui(ui);
+                ui.separator();
+                ui.checkbox(&mut self.renderer.orbit_lights, "orbit lights");
+                self.renderer.lights.ui(ui);
+                ui.separator();
+                let mut mode = self.renderer.observer.mode();
+                ui.horizontal(|ui| {
+                    ui.label("camera");
+                    ui.selectable_value(&mut mode, CameraMode::Fly, "fly");
+                    ui.selectable_value(&mut mode, CameraMode::Orbit, "orbit");
+                });
+                self.renderer.observer.set_mode(mode);
+                let controlls = &mut self.renderer.observer.controlls;
+                ui.add(egui::Slider::new(&mut controlls.max_speed, 0.5..=50.0).logarithmic(true).text("max speed"));
+                ui.add(egui::Slider::new(&mut controlls.acceleration, 1.0..=200.0).logarithmic(true).text("acceleration"));
+                ui.add(egui::Slider::new(&mut controlls.sensitivity, 0.05..=2.0).text("mouse sensitivity"));
+                ui.add(egui::Slider::new(&mut controlls.look_smoothing, 0.0..=0.5).text("look smoothing (s)"));
+                let mut projection = self.renderer.observer.projection.kind();
+                ui.horizontal(|ui| {
+                    ui.label("projection");
+                    ui.selectable_value(&mut projection, ProjectionKind::Perspective, "perspective");
+                    ui.selectable_value(&mut projection, ProjectionKind::Orthographic, "orthographic");
+                });
+                ui.horizontal(|ui| {
+                    ui.label("view");
+                    // the presets are for inspecting the scene, so they show it orthographically
+                    for (preset, name) in [(ViewPreset::Top, "top"), (ViewPreset::Front, "front"), (ViewPreset::Side, "side")] {
+                        if ui.button(name).clicked() {
+                            self.renderer.observer.look_along(preset);
+                            projection = ProjectionKind::Orthographic;
+                        }
+                    }
+                });
+                self.renderer.observer.set_projection(projection);
+                if projection == ProjectionKind::Orthographic {
+                    let mut height = self.renderer.observer.projection.orthographic_height();
+                    ui.add(egui::Slider::new(&mut height, 0.1..=200.0).logarithmic(true).text("view height"));
+                    self.renderer.observer.projection.set_orthographic_height(height);
+                }
+                ui.collapsing("camera path", |ui| {
+                    self.renderer.flythrough.ui(ui, &self.renderer.observer);
+                });
+                if let Some(id) = self.renderer.selected_node() {
+                    let instance = match self.renderer.selected {
+                        Some(Selection::Animated(index)) => format!(", animated instance {}", index),
+                        _ => String::new(),
+                    };
+                    let node = self.renderer.scene.node_mut(id);
+                    ui.horizontal(|ui| {
+                        ui.label(format!("selected: {}{}", node.name, instance));
+                        if ui.button("deselect").clicked() {
+                            self.renderer.selected = None;
+                        }
+                    });
+                    node.transform.ui(ui);
+                    if ui.button("frame selection").clicked() {
+                        self.renderer.frame_selection();
+                    }
+                    ui.separator();
+                }
+                ui.checkbox(&mut self.renderer.frustum_culling, "frustum culling");
+                let (visible, culled) = self.renderer.culling_stats();
+                ui.label(format!("{} instances visible, {} culled", visible, culled));
+                self.renderer.scene.ui(ui);
+                if ui.button(format!("save scene to {}", self.scene_path.display())).clicked() {
+                    if let Err(e) = self.renderer.describe_scene().save(&self.scene_path) {
+                        log::error!("{:?}", e);
+                    }
+                }
             });
 
         // End the UI frame. We could now handle the output and draw the UI with the backend.
@@ -450,9 +361,9 @@ impl State {
         };
         let tdelta: egui::TexturesDelta = full_output.textures_delta;
         self.ui_render_pass
-            .add_textures(&self.device, &self.queue, &tdelta)
+            .add_textures(&self.renderer.device, &self.renderer.queue, &tdelta)
             .expect("add texture ok");
-        self.ui_render_pass.update_buffers(&self.device, &self.queue, &paint_jobs, &screen_descriptor);
+        self.ui_render_pass.update_buffers(&self.renderer.device, &self.renderer.queue, &paint_jobs, &screen_descriptor);
 
         // Record all render passes.
         self.ui_render_pass
@@ -466,7 +377,7 @@ impl State {
             .unwrap();
 
         // Submit the commands.
-        self.queue.submit(iter::once(encoder.finish()));
+        self.renderer.queue.submit(iter::once(encoder.finish()));
 
         // Redraw egui
         output.present();
@@ -486,8 +397,37 @@ impl State {
     }
 }
 
+/// Replace the scene of the renderer with the one from a scene file
+async fn load_scene(renderer: &mut Renderer, path: &Path) -> anyhow::Result<()> {
+    let description = SceneDescription::load(path)?;
+    renderer.load_scene(&description).await
+}
+
+/// Render a single frame of the scene without opening a window and write it to `path`
+/// as a PNG. This is meant for CI and batch jobs, so it falls back to a software adapter
+/// if there is no GPU. With a `scene` file that scene is rendered instead of the default one.
+pub async fn run_headless<P: AsRef<Path>>(
+    width: u32,
+    height: u32,
+    force_fallback_adapter: bool,
+    scene: Option<PathBuf>,
+    path: P,
+) -> anyhow::Result<()> {
+    let mut headless = HeadlessRenderer::new(width, height, force_fallback_adapter).await?;
+    if let Some(scene) = scene {
+        load_scene(&mut headless.renderer, &scene).await?;
+    }
+    headless.render_to_png(path)
+}
+
 #[cfg_attr(target_arch="wasm32", wasm_bindgen(start))]
 pub async fn run() {
+    run_with_scene(None).await
+}
+
+/// Open the window and start with the scene from the given scene file, or the default
+/// scene if there is none
+pub async fn run_with_scene(scene: Option<PathBuf>) {
     cfg_if::cfg_if! {
         if #[cfg(target_arch = "wasm32")] {
             std::panic::set_hook(Box::new(console_error_panic_hook::hook));
@@ -520,7 +460,7 @@ pub async fn run() {
     }
     
     // State::new uses async code, so we're going to wait for it to finish
-    let mut state = State::new(window).await;
+    let mut state = State::new(window, scene).await;
     let mut last_render_time = Instant::now();
 
     // This is where events are processed and the resulting frames rendered
@@ -532,9 +472,6 @@ pub async fn run() {
             let event_handeled_by_app = state.input(&event);
             if !event_handeled_by_app {
                 match event {
-                    Event::DeviceEvent { event: DeviceEvent::MouseMotion { delta, }, .. } => if state.mouse_pressed {
-                        state.observer.controlls.process_mouse_movement(delta.0, delta.1)
-                    }
                     Event::WindowEvent {
                         ref event,
                         window_id,
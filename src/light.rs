@@ -1,81 +1,353 @@
-use bytemuck;
+use std::mem;
+use crate::shadow;
 
-// this trait is the thing that allows for the 'device.create_buffer_init' function
-use wgpu::util::DeviceExt;
-use wgpu;
+/// The different kinds of lights. The discriminant is what the shader sees in `light_type`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum LightType {
+    /// Shines in all directions from its position, the intensity falls off with distance
+    Point = 0,
+    /// Infinitely far away like the sun, only the direction matters and there is no falloff
+    Directional = 1,
+    /// A point light that only shines into a cone around its direction
+    Spot = 2,
+}
+
+impl LightType {
+    pub const ALL: [LightType; 3] = [LightType::Point, LightType::Directional, LightType::Spot];
+
+    /// Number of shadow map layers a light of this type needs
+    pub fn shadow_layers(&self) -> u32 {
+        match self {
+            LightType::Point => 6,
+            LightType::Directional | LightType::Spot => 1,
+        }
+    }
 
+    fn from_u32(value: u32) -> Self {
+        match value {
+            1 => LightType::Directional,
+            2 => LightType::Spot,
+            _ => LightType::Point,
+        }
+    }
+}
+
+/// The GPU representation of a light. Not every field is used by every type of light:
+/// directional lights ignore the position (it is only where the gizmo is drawn) and the
+/// attenuation, point lights ignore the direction and the cone angles.
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct LightUniform {
     pub position: [f32; 3],
-    _padding: u32,
+    light_type: u32,
     pub color: [f32; 3],
-    _padding2: u32,
+    pub intensity: f32,
+    /// The direction the light is shining in (directional and spot lights)
+    pub direction: [f32; 3],
+    // the first layer in the shadow map that belongs to this light
+    shadow_layer: u32,
+    /// constant, linear and quadratic coefficient of the distance attenuation
+    /// 1 / (c + l * d + q * d²) of point and spot lights
+    pub attenuation: [f32; 3],
+    /// Angle (in radians) from the direction up to which a spot light shines at full intensity
+    pub inner_cone_angle: f32,
+    /// Angle (in radians) from the direction at which a spot light has faded out completely
+    pub outer_cone_angle: f32,
+    _padding: [u32; 3],
+}
+
+impl LightUniform {
+    pub fn point(position: [f32; 3], color: [f32; 3], intensity: f32) -> Self {
+        Self {
+            position,
+            light_type: LightType::Point as u32,
+            color,
+            intensity,
+            direction: [0.0, -1.0, 0.0],
+            shadow_layer: shadow::NO_SHADOW,
+            attenuation: [0.0, 0.0, 1.0],
+            inner_cone_angle: 0.0,
+            outer_cone_angle: 0.0,
+            _padding: [0; 3],
+        }
+    }
+
+    pub fn directional(direction: [f32; 3], color: [f32; 3], intensity: f32) -> Self {
+        Self {
+            light_type: LightType::Directional as u32,
+            direction,
+            attenuation: [1.0, 0.0, 0.0],
+            ..Self::point([0.0, 10.0, 0.0], color, intensity)
+        }
+    }
+
+    pub fn spot<A: Into<cgmath::Rad<f32>>>(
+        position: [f32; 3],
+        direction: [f32; 3],
+        inner_cone_angle: A,
+        outer_cone_angle: A,
+        color: [f32; 3],
+        intensity: f32,
+    ) -> Self {
+        Self {
+            light_type: LightType::Spot as u32,
+            direction,
+            inner_cone_angle: inner_cone_angle.into().0,
+            outer_cone_angle: outer_cone_angle.into().0,
+            ..Self::point(position, color, intensity)
+        }
+    }
+
+    pub fn light_type(&self) -> LightType {
+        LightType::from_u32(self.light_type)
+    }
+
+    pub fn set_light_type(&mut self, light_type: LightType) {
+        self.light_type = light_type as u32;
+    }
+
+    /// The first shadow map layer of the light, if it got any
+    pub fn shadow_layer(&self) -> Option<u32> {
+        if self.shadow_layer == shadow::NO_SHADOW {
+            None
+        } else {
+            Some(self.shadow_layer)
+        }
+    }
+
+    /// Controls for all the parameters that the type of the light uses
+    fn ui(&mut self, ui: &mut egui::Ui) -> bool {
+        let mut changed = false;
+        let mut light_type = self.light_type();
+        egui::ComboBox::from_label("type")
+            .selected_text(format!("{:?}", light_type))
+            .show_ui(ui, |ui| {
+                for t in LightType::ALL {
+                    changed |= ui.selectable_value(&mut light_type, t, format!("{:?}", t)).changed();
+                }
+            });
+        self.set_light_type(light_type);
+
+        ui.horizontal(|ui| {
+            ui.label("position");
+            for coord in self.position.iter_mut() {
+                changed |= ui.add(egui::DragValue::new(coord).speed(0.1)).changed();
+            }
+        });
+        if light_type != LightType::Point {
+            ui.horizontal(|ui| {
+                ui.label("direction");
+                for coord in self.direction.iter_mut() {
+                    changed |= ui.add(egui::DragValue::new(coord).speed(0.05)).changed();
+                }
+            });
+        }
+        ui.horizontal(|ui| {
+            ui.label("color");
+            changed |= ui.color_edit_button_rgb(&mut self.color).changed();
+            changed |= ui.add(egui::DragValue::new(&mut self.intensity).speed(0.1).clamp_range(0.0..=1000.0).prefix("intensity ")).changed();
+        });
+        if light_type != LightType::Directional {
+            ui.horizontal(|ui| {
+                ui.label("attenuation");
+                for coef in self.attenuation.iter_mut() {
+                    changed |= ui.add(egui::DragValue::new(coef).speed(0.01).clamp_range(0.0..=10.0)).changed();
+                }
+            });
+        }
+        if light_type == LightType::Spot {
+            ui.horizontal(|ui| {
+                ui.label("cone");
+                changed |= ui.drag_angle(&mut self.inner_cone_angle).changed();
+                changed |= ui.drag_angle(&mut self.outer_cone_angle).changed();
+            });
+            self.inner_cone_angle = self.inner_cone_angle.clamp(0.0, std::f32::consts::FRAC_PI_2);
+            self.outer_cone_angle = self.outer_cone_angle.clamp(self.inner_cone_angle, std::f32::consts::FRAC_PI_2);
+        }
+        changed
+    }
+}
+
+/// The header of the light storage buffer. The shader sees the buffer as
+/// `struct Lights { count: u32, lights: array<Light> }` and the array is aligned to 16 bytes,
+/// so the count gets padded to 16 bytes.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct LightsHeader {
+    count: u32,
+    _padding: [u32; 3],
 }
 
-pub struct Light {
-    pub uniform: LightUniform,
+/// All the lights in the scene. The lights live in a storage buffer on the GPU so that
+/// there can be an arbitrary number of them. Lights can be added, removed and modified at
+/// any time, the changes are sent to the GPU with the next call to `update_gpu_state`.
+pub struct Lights {
+    lights: Vec<LightUniform>,
     buffer: wgpu::Buffer,
+    // number of lights that fit into the buffer
+    capacity: usize,
+    dirty: bool,
     pub bind_group_layout: wgpu::BindGroupLayout,
     pub bind_group: wgpu::BindGroup,
 }
 
-impl Light {
-    pub fn new(device: &mut wgpu::Device) -> Self {
-        let lu = LightUniform {
-                position: [2.0, 2.0, 2.0],
-                _padding: 0,
-                color: [1., 1., 1.],
-                _padding2: 0,
-        };
-        let light_buffer = device.create_buffer_init(
-            &wgpu::util::BufferInitDescriptor{
-                label: Some("light"),
-                contents: bytemuck::cast_slice(&[lu]),
-                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-            }
-        );
+impl Lights {
+    pub fn new(device: &wgpu::Device) -> Self {
         let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-            label: None, 
+            label: Some("lights bind group layout"),
             entries: &[wgpu::BindGroupLayoutEntry{
                 binding: 0,
                 visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
                 ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Uniform,
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
                     has_dynamic_offset: false,
                     min_binding_size: None
                 },
                 count: None,
             }],
         });
+        let capacity = 4;
+        let (buffer, bind_group) = Self::create_buffer(device, &bind_group_layout, capacity);
+        let mut lights = Lights {
+            lights: Vec::new(),
+            buffer,
+            capacity,
+            dirty: true,
+            bind_group_layout,
+            bind_group,
+        };
+        lights.add(LightUniform::point([2.0, 2.0, 2.0], [1., 1., 1.], 3.0));
+        lights
+    }
+
+    fn create_buffer(device: &wgpu::Device, layout: &wgpu::BindGroupLayout, capacity: usize) -> (wgpu::Buffer, wgpu::BindGroup) {
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("lights"),
+            size: (mem::size_of::<LightsHeader>() + capacity * mem::size_of::<LightUniform>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
         let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: None,
-            layout: &bind_group_layout,
+            label: Some("lights bind group"),
+            layout,
             entries: &[wgpu::BindGroupEntry {
                 binding: 0,
-                resource: light_buffer.as_entire_binding(),
+                resource: buffer.as_entire_binding(),
             }],
         });
-        Light { uniform: lu, buffer: light_buffer, bind_group_layout, bind_group }
-    }
-    pub fn update(&mut self, position: Option<[f32; 3]>, color: Option<[f32; 3]>, dev_queue: &wgpu::Queue) {
-        let mut write_buffer = false;
-        match position {
-            Some(pos) => {
-                self.uniform.position = pos;
-                write_buffer = true;
-            },
-            None => {}
-        };
-        match color {
-            Some(col) => {
-                self.uniform.color = col;
-                write_buffer = true;
-            },
-            None => {}
-        };
-        if write_buffer {
-            dev_queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[self.uniform]));
+        (buffer, bind_group)
+    }
+
+    pub fn len(&self) -> usize {
+        self.lights.len()
+    }
+
+    /// Add a light to the scene and return its index
+    pub fn add(&mut self, light: LightUniform) -> usize {
+        self.lights.push(light);
+        self.dirty = true;
+        self.lights.len() - 1
+    }
+
+    /// Remove all the lights
+    pub fn clear(&mut self) {
+        self.dirty = true;
+        self.lights.clear();
+    }
+
+    /// Remove a light from the scene. The lights after it move down one index.
+    pub fn remove(&mut self, index: usize) -> LightUniform {
+        self.dirty = true;
+        self.lights.remove(index)
+    }
+
+    /// The lights in the order they were added
+    pub fn iter(&self) -> impl Iterator<Item = &LightUniform> {
+        self.lights.iter()
+    }
+
+    /// Modify the lights in place, they are all considered changed afterwards
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut LightUniform> {
+        self.dirty = true;
+        self.lights.iter_mut()
+    }
+
+    /// Send the lights to the GPU if they changed. If there are more lights than fit
+    /// into the buffer a bigger one is created, which also replaces the bind group.
+    pub fn update_gpu_state(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        if !self.dirty {
+            return;
+        }
+        // hand out the shadow map layers in order until they run out
+        let mut next_layer = 0;
+        for light in self.lights.iter_mut() {
+            let layers = light.light_type().shadow_layers();
+            if next_layer + layers <= shadow::SHADOW_MAP_LAYERS {
+                light.shadow_layer = next_layer;
+                next_layer += layers;
+            } else {
+                light.shadow_layer = shadow::NO_SHADOW;
+            }
+        }
+        if self.lights.len() > self.capacity {
+            self.capacity = self.lights.len().next_power_of_two();
+            let (buffer, bind_group) = Self::create_buffer(device, &self.bind_group_layout, self.capacity);
+            self.buffer = buffer;
+            self.bind_group = bind_group;
+        }
+        let header = LightsHeader { count: self.lights.len() as u32, _padding: [0; 3] };
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[header]));
+        if !self.lights.is_empty() {
+            queue.write_buffer(
+                &self.buffer,
+                mem::size_of::<LightsHeader>() as wgpu::BufferAddress,
+                bytemuck::cast_slice(&self.lights),
+            );
+        }
+        self.dirty = false;
+    }
+
+    /// Controls for adding, removing and editing the lights
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        let mut remove = None;
+        for (i, light) in self.lights.iter_mut().enumerate() {
+            egui::CollapsingHeader::new(format!("light {}", i))
+                .id_source(("light", i))
+                .show(ui, |ui| {
+                    self.dirty |= light.ui(ui);
+                    if ui.button("remove").clicked() {
+                        remove = Some(i);
+                    }
+                });
         }
+        if let Some(i) = remove {
+            self.remove(i);
+        }
+        ui.horizontal(|ui| {
+            if ui.button("add point light").clicked() {
+                self.add(LightUniform::point([0.0, 2.0, 0.0], [1., 1., 1.], 3.0));
+            }
+            if ui.button("add sun").clicked() {
+                self.add(LightUniform::directional([-0.3, -1.0, -0.2], [1., 1., 1.], 0.5));
+            }
+            if ui.button("add spot light").clicked() {
+                self.add(LightUniform::spot([0.0, 4.0, 0.0], [0.0, -1.0, 0.0], cgmath::Deg(20.0), cgmath::Deg(30.0), [1., 1., 1.], 10.0));
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn light_uniform_matches_the_shader() {
+        // the angles follow the vec3 of the attenuation directly, like in struct Light
+        // in common.wgsl, and the struct is padded to the 16 byte alignment of the vec3s
+        assert_eq!(mem::offset_of!(LightUniform, attenuation), 48);
+        assert_eq!(mem::offset_of!(LightUniform, inner_cone_angle), 60);
+        assert_eq!(mem::offset_of!(LightUniform, outer_cone_angle), 64);
+        assert_eq!(mem::size_of::<LightUniform>(), 80);
     }
 }
@@ -6,11 +6,23 @@ var<uniform> observer: Observer;
 
 struct Light {
 	position: vec3<f32>,
+	light_type: u32,
 	color: vec3<f32>,
+	intensity: f32,
+	direction: vec3<f32>,
+	shadow_layer: u32,
+	attenuation: vec3<f32>,
+	inner_cone_angle: f32,
+	outer_cone_angle: f32,
+}
+
+struct Lights {
+	count: u32,
+	lights: array<Light>,
 }
 
 @group(1) @binding(0)
-var<uniform> light: Light;
+var<storage, read> lights: Lights;
 
 struct VertexInput {
 	@location(0) position: vec3<f32>,
@@ -24,8 +36,11 @@ struct VertexOutput {
 @vertex
 fn vs_main(
 	model: VertexInput,
+	// every light is drawn as its own instance
+	@builtin(instance_index) light_index: u32,
 ) -> VertexOutput {
 	let scale = 0.2;
+	let light = lights.lights[light_index];
 	var out: VertexOutput;
 	out.clip_position = observer.view_projection * vec4(model.position * scale + light.position, 1.0);
 	out.color = light.color;
@@ -1,5 +1,39 @@
-use learn_wgpu::run;
+use learn_wgpu::{run_headless, run_with_scene};
+
+const USAGE: &str = "usage: learn-wgpu [--scene <scene.ron>] [--headless <output.png> [--size <width>x<height>] [--fallback]]";
 
 fn main() {
-    pollster::block_on(run());
+    let mut headless_output = None;
+    let mut size = (800, 600);
+    let mut force_fallback_adapter = false;
+    let mut scene = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--headless" => headless_output = Some(args.next().expect(USAGE)),
+            "--size" => {
+                let value = args.next().expect(USAGE);
+                let (width, height) = value.split_once('x').expect(USAGE);
+                size = (width.parse().expect(USAGE), height.parse().expect(USAGE));
+            }
+            "--fallback" => force_fallback_adapter = true,
+            "--scene" => scene = Some(args.next().expect(USAGE).into()),
+            _ => {
+                eprintln!("{}", USAGE);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    match headless_output {
+        Some(path) => {
+            env_logger::init();
+            if let Err(e) = pollster::block_on(run_headless(size.0, size.1, force_fallback_adapter, scene, path)) {
+                eprintln!("headless rendering failed: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+        None => pollster::block_on(run_with_scene(scene)),
+    }
 }
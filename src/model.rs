@@ -1,5 +1,7 @@
 use core::ops::Range;
-use std::collections::HashMap;
+use std::rc::Rc;
+use wgpu::util::DeviceExt;
+use crate::bounds::{Aabb, BoundingSphere, Ray};
 use crate::texture;
 
 pub trait GPUVertex {
@@ -16,6 +18,8 @@ pub struct ModelVertex {
     pub position: [f32; 3],
     pub tex_coords: [f32; 2],
     pub normal: [f32; 3],
+    pub tangent: [f32; 3],
+    pub bitangent: [f32; 3],
 }
 
 impl GPUVertex for ModelVertex {
@@ -40,17 +44,250 @@ impl GPUVertex for ModelVertex {
                     shader_location: 2,
                     format: wgpu::VertexFormat::Float32x3,
                 },
+                // tangent and bitangent span the tangent space of the surface together
+                // with the normal, they are needed to orient the normal map samples
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
+                    shader_location: 3,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 11]>() as wgpu::BufferAddress,
+                    shader_location: 4,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
             ],
         }
     }
 }
 
+/// The parameters of a material from the MTL file, bound next to its textures.
+/// The diffuse color is multiplied with the diffuse texture.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct MaterialUniform {
+    /// Ka
+    pub ambient: [f32; 3],
+    /// Ns, the exponent of the specular highlight
+    pub shininess: f32,
+    /// Kd
+    pub diffuse: [f32; 3],
+    /// d, 1.0 is fully opaque
+    pub opacity: f32,
+    /// Ks
+    pub specular: [f32; 3],
+    _padding: u32,
+    /// Ke, the light the material gives off by itself
+    pub emissive: [f32; 3],
+    _padding2: u32,
+}
+
+impl MaterialUniform {
+    pub fn new(
+        ambient: [f32; 3],
+        diffuse: [f32; 3],
+        specular: [f32; 3],
+        shininess: f32,
+        opacity: f32,
+        emissive: [f32; 3],
+    ) -> Self {
+        Self {
+            ambient,
+            shininess,
+            diffuse,
+            opacity,
+            specular,
+            _padding: 0,
+            emissive,
+            _padding2: 0,
+        }
+    }
+}
+
+/// The factors of a metallic-roughness material, they are multiplied with the values
+/// sampled from the textures of the material.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct PbrMaterialUniform {
+    /// linear color and alpha
+    pub base_color: [f32; 4],
+    pub emissive: [f32; 3],
+    pub metallic: f32,
+    pub roughness: f32,
+    /// 0.0 ignores the occlusion texture, 1.0 applies it fully
+    pub occlusion_strength: f32,
+    _padding: [u32; 2],
+}
+
+impl PbrMaterialUniform {
+    pub fn new(base_color: [f32; 4], metallic: f32, roughness: f32, occlusion_strength: f32, emissive: [f32; 3]) -> Self {
+        Self {
+            base_color,
+            emissive,
+            metallic,
+            roughness,
+            occlusion_strength,
+            _padding: [0; 2],
+        }
+    }
+}
+
+/// The textures of a metallic-roughness material. Metallic is read from the blue channel
+/// and roughness from the green channel (so a packed glTF texture can be used for both),
+/// the occlusion from the red channel.
+pub struct PbrTextures {
+    pub base_color: texture::Texture,
+    pub normal: texture::Texture,
+    pub metallic: texture::Texture,
+    pub roughness: texture::Texture,
+    pub occlusion: texture::Texture,
+    pub emissive: texture::Texture,
+}
+
+/// How a material is shaded, this decides which pipeline its meshes are drawn with
+pub enum Shading {
+    Phong(MaterialUniform),
+    Pbr(PbrMaterialUniform),
+}
+
+/// The layouts of the material bind groups (group 0) of the Phong and the PBR pipeline.
+/// Both have pairs of texture and sampler followed by the uniform with the parameters.
+pub struct MaterialLayouts {
+    pub phong: wgpu::BindGroupLayout,
+    pub pbr: wgpu::BindGroupLayout,
+}
+
+impl MaterialLayouts {
+    // diffuse and normal map
+    const PHONG_TEXTURES: u32 = 2;
+    // base color, normal, metallic, roughness, occlusion and emissive
+    const PBR_TEXTURES: u32 = 6;
+
+    pub fn new(device: &wgpu::Device) -> Self {
+        Self {
+            phong: Self::create_layout(device, Self::PHONG_TEXTURES, "phong material bind group layout"),
+            pbr: Self::create_layout(device, Self::PBR_TEXTURES, "pbr material bind group layout"),
+        }
+    }
+
+    fn create_layout(device: &wgpu::Device, textures: u32, label: &str) -> wgpu::BindGroupLayout {
+        let mut entries = (0..textures).flat_map(|i| [
+            wgpu::BindGroupLayoutEntry {
+                binding: 2 * i,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 2 * i + 1,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+        ]).collect::<Vec<_>>();
+        // the parameters of the material
+        entries.push(wgpu::BindGroupLayoutEntry {
+            binding: 2 * textures,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        });
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some(label),
+            entries: &entries,
+        })
+    }
+}
+
+/// A material of an object, the name it is created with only labels its GPU resources
 pub struct Material {
-    pub name: String,
-    pub diffuse_texture: texture::Texture,
+    pub shading: Shading,
+    /// the textures, in the order they are bound in, and the parameters are only used
+    /// through the bind group, they are kept so they live as long as the material
+    _textures: Vec<texture::Texture>,
+    _uniform_buffer: wgpu::Buffer,
     pub bind_group: wgpu::BindGroup,
 }
 
+impl Material {
+    /// A material for the Phong pipeline with a diffuse texture and a normal map
+    pub fn phong(
+        device: &wgpu::Device,
+        layouts: &MaterialLayouts,
+        name: String,
+        diffuse_texture: texture::Texture,
+        normal_texture: texture::Texture,
+        uniform: MaterialUniform,
+    ) -> Self {
+        Self::new(device, &layouts.phong, name, vec![diffuse_texture, normal_texture], Shading::Phong(uniform))
+    }
+
+    /// A material for the PBR pipeline
+    pub fn pbr(
+        device: &wgpu::Device,
+        layouts: &MaterialLayouts,
+        name: String,
+        textures: PbrTextures,
+        uniform: PbrMaterialUniform,
+    ) -> Self {
+        let textures = vec![
+            textures.base_color,
+            textures.normal,
+            textures.metallic,
+            textures.roughness,
+            textures.occlusion,
+            textures.emissive,
+        ];
+        Self::new(device, &layouts.pbr, name, textures, Shading::Pbr(uniform))
+    }
+
+    fn new(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        name: String,
+        textures: Vec<texture::Texture>,
+        shading: Shading,
+    ) -> Self {
+        let contents = match &shading {
+            Shading::Phong(uniform) => bytemuck::bytes_of(uniform),
+            Shading::Pbr(uniform) => bytemuck::bytes_of(uniform),
+        };
+        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{} Material Buffer", name)),
+            contents,
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        let mut entries = textures.iter().enumerate().flat_map(|(i, texture)| [
+            wgpu::BindGroupEntry {
+                binding: 2 * i as u32,
+                resource: wgpu::BindingResource::TextureView(&texture.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2 * i as u32 + 1,
+                resource: wgpu::BindingResource::Sampler(&texture.sampler),
+            },
+        ]).collect::<Vec<_>>();
+        entries.push(wgpu::BindGroupEntry {
+            binding: 2 * textures.len() as u32,
+            resource: uniform_buffer.as_entire_binding(),
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some(&format!("{} Material Bind Group", name)),
+            layout,
+            entries: &entries,
+        });
+        Self { shading, _textures: textures, _uniform_buffer: uniform_buffer, bind_group }
+    }
+}
+
 /// The structure representing an asset in a scene. It contains the 3D geomtry of the thing
 /// along with the Materials that it is made up of. One Mesh can only be linked to one material.
 /// Something like a charackter in a game would consist of many different meshes that map to
@@ -59,7 +296,72 @@ pub struct Material {
 /// immutable while the instance may be modified through the duration of the scene.
 pub struct Object {
     pub meshes: Vec<Mesh>,
-    pub materials: Vec<Material>,
+    /// the objects of a glTF file share its materials
+    pub materials: Vec<Rc<Material>>,
+    /// the bounds of all the meshes together, in the space of the object
+    pub aabb: Aabb,
+    pub bounding_sphere: BoundingSphere,
+}
+
+impl Object {
+    pub fn new(meshes: Vec<Mesh>, materials: Vec<Rc<Material>>) -> Self {
+        let aabb = meshes.iter().fold(Aabb::empty(), |aabb, mesh| aabb.union(&mesh.aabb));
+        // a sphere around the spheres of the meshes is looser than one around the box
+        let bounding_sphere = meshes.iter()
+            .map(|mesh| mesh.bounding_sphere)
+            .reduce(|a, b| a.union(&b))
+            .unwrap_or_else(|| BoundingSphere::from_points(Vec::new()));
+        Self { meshes, materials, aabb, bounding_sphere }
+    }
+
+    /// The distance along the ray to the closest triangle it hits, the ray has to be in the
+    /// space of the object
+    pub fn intersect_ray(&self, ray: &Ray) -> Option<f32> {
+        let mut closest: Option<f32> = None;
+        for mesh in &self.meshes {
+            for triangle in mesh.indices.chunks_exact(3) {
+                let [a, b, c] = [0, 1, 2].map(|i| mesh.positions[triangle[i] as usize]);
+                if let Some(distance) = ray.intersect_triangle(a, b, c) {
+                    if closest.is_none_or(|closest| distance < closest) {
+                        closest = Some(distance);
+                    }
+                }
+            }
+        }
+        closest
+    }
+}
+
+/// A node of a model file, the transform is relative to the parent node. The node hierarchy
+/// of a glTF file is kept like this, so that it becomes part of the scene graph.
+pub struct ModelNode {
+    pub name: String,
+    pub transform: Instance,
+    /// index into the objects of the model file
+    pub object: Option<usize>,
+    pub children: Vec<ModelNode>,
+}
+
+/// Everything that is loaded from a model file: the objects and the nodes that place them.
+/// An OBJ file is a single object on a single node.
+pub struct ModelFile {
+    pub objects: Vec<Object>,
+    pub nodes: Vec<ModelNode>,
+}
+
+impl ModelFile {
+    /// A model file that is only one object, without a transform of its own
+    pub fn single(name: &str, object: Object) -> Self {
+        Self {
+            objects: vec![object],
+            nodes: vec![ModelNode {
+                name: name.to_string(),
+                transform: Instance::identity(),
+                object: Some(0),
+                children: Vec::new(),
+            }],
+        }
+    }
 }
 
 /// The Mesh is the struct that manages the GPU memory associated with the Mesh Data.
@@ -67,41 +369,103 @@ pub struct Object {
 /// The Material link is the index of the shader bind group containing the corresponding
 /// Material mesh
 pub struct Mesh {
-    pub name: String,
     pub vertex_buffer: wgpu::Buffer,
     pub index_buffer: wgpu::Buffer,
     pub num_elements: u32,
     pub material: usize,
+    /// the bounds of the vertices, in the space of the object
+    pub aabb: Aabb,
+    pub bounding_sphere: BoundingSphere,
+    /// the vertex positions and the triangles are kept in main memory for picking
+    pub positions: Vec<cgmath::Point3<f32>>,
+    pub indices: Vec<u32>,
 }
 
 /// This is the description of the instance of a model. Instances will be the things
 /// that will be modifiable from the mathematical "model" of the scene
+#[derive(Clone)]
 pub struct Instance {
     pub position: cgmath::Vector3<f32>,
     pub rotation: cgmath::Quaternion<f32>,
     pub scale: cgmath::Vector3<f32>
 }
 
-/// Construct the Data that is sent to the GPU from the Main Memmory representation
-/// The instances are kept in Main memory as they are expected to be modified by the mathematical
-/// model that animates the scene
 impl Instance {
-    pub fn to_shader_format(&self) -> GPUInstance {
-        GPUInstance{
-            rotlate: (cgmath::Matrix4::from_translation(self.position) * cgmath::Matrix4::from(self.rotation)).into(),
-            scale: [self.scale.x, self.scale.y, self.scale.z, 1.0],
+    /// Leaves the model where it is
+    pub fn identity() -> Self {
+        Self {
+            position: cgmath::Vector3::new(0.0, 0.0, 0.0),
+            rotation: cgmath::Quaternion::new(1.0, 0.0, 0.0, 0.0),
+            scale: cgmath::Vector3::new(1.0, 1.0, 1.0),
+        }
+    }
+
+    /// The transform that scales, then rotates and then moves the model
+    pub fn to_matrix(&self) -> cgmath::Matrix4<f32> {
+        cgmath::Matrix4::from_translation(self.position)
+            * cgmath::Matrix4::from(self.rotation)
+            * cgmath::Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
+
+    /// Controls for the position, the rotation (as Euler angles) and the scale
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.label("position");
+            for coord in [&mut self.position.x, &mut self.position.y, &mut self.position.z] {
+                ui.add(egui::DragValue::new(coord).speed(0.1));
+            }
+        });
+        // the angles are only written back when they were edited, so that the round trip
+        // through the Euler angles does not disturb the rotation
+        let euler = cgmath::Euler::from(self.rotation);
+        let mut angles = [euler.x, euler.y, euler.z].map(|angle| cgmath::Deg::from(angle).0);
+        let mut rotated = false;
+        ui.horizontal(|ui| {
+            ui.label("rotation");
+            for angle in angles.iter_mut() {
+                rotated |= ui.add(egui::DragValue::new(angle).speed(1.0).suffix("°")).changed();
+            }
+        });
+        if rotated {
+            let [x, y, z] = angles.map(cgmath::Deg);
+            self.rotation = cgmath::Euler::new(x, y, z).into();
         }
+        ui.horizontal(|ui| {
+            ui.label("scale");
+            for coord in [&mut self.scale.x, &mut self.scale.y, &mut self.scale.z] {
+                ui.add(egui::DragValue::new(coord).speed(0.05));
+            }
+        });
     }
 }
 
+/// The matrix that transforms normals, the inverse transpose of the linear part of the
+/// transform. Normals have to be transformed with it to stay perpendicular to the surface
+/// when the transform scales unevenly.
+pub fn normal_matrix(transform: cgmath::Matrix4<f32>) -> cgmath::Matrix3<f32> {
+    use cgmath::{Matrix, SquareMatrix};
+    let linear = cgmath::Matrix3::from_cols(transform.x.truncate(), transform.y.truncate(), transform.z.truncate());
+    linear.invert().unwrap_or(linear).transpose()
+}
+
 #[repr(C)]
 #[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct GPUInstance {
-    rotlate: [[f32; 4]; 4],
-    scale: [f32; 4],
+    model: [[f32; 4]; 4],
+    // the columns are padded to four floats, like a mat3x3 in a storage buffer
+    normal: [[f32; 4]; 3],
 }
 
 impl GPUInstance {
+    /// The instance data for a model placed by the given world transform
+    pub fn from_matrix(transform: cgmath::Matrix4<f32>) -> Self {
+        let normal = normal_matrix(transform);
+        Self {
+            model: transform.into(),
+            normal: [normal.x.extend(0.0).into(), normal.y.extend(0.0).into(), normal.z.extend(0.0).into()],
+        }
+    }
+
     /// Generate the layout for the Vertex Buffer used to store the instance transformation matrix
     /// on the gpu
     pub fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
@@ -135,24 +499,28 @@ impl GPUInstance {
                     shader_location: 8,
                     format: wgpu::VertexFormat::Float32x4,
                 },
+                // the columns of the normal matrix
                 wgpu::VertexAttribute {
                     offset: mem::size_of::<[f32; 16]>() as wgpu::BufferAddress,
                     shader_location: 9,
                     format: wgpu::VertexFormat::Float32x4,
                 },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 20]>() as wgpu::BufferAddress,
+                    shader_location: 10,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 24]>() as wgpu::BufferAddress,
+                    shader_location: 11,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
             ],
         }
     }
 }
 
 pub trait DrawModel<'a> {
-    fn draw_mesh(
-        &mut self, 
-        mesh: &'a Mesh,
-        material: &'a Material, 
-        camera_bind_group: &'a wgpu::BindGroup,
-        light_bind_group: &'a wgpu::BindGroup,
-    );
     fn draw_mesh_instanced(
         &mut self,
         mesh: &'a Mesh,
@@ -161,35 +529,11 @@ pub trait DrawModel<'a> {
         camera_bind_group: &'a wgpu::BindGroup,
         light_bind_group: &'a wgpu::BindGroup,
     );
-
-    fn draw_model(
-        &mut self,
-        model: &'a Object,
-        camera_bind_group: &'a wgpu::BindGroup,
-        light_bind_group: &'a wgpu::BindGroup,
-    );
-    fn draw_model_instanced(
-        &mut self,
-        model: &'a Object,
-        instances: Range<u32>,
-        camera_bind_group: &'a wgpu::BindGroup,
-        light_bind_group: &'a wgpu::BindGroup,
-    );
 }
 
 impl<'a, 'b> DrawModel<'b> for wgpu::RenderPass<'a>
     where 'b: 'a,
 {
-    fn draw_mesh(
-        &mut self,
-        mesh: &'b Mesh,
-        material: &'b Material,
-        camera_bind_group: &'b wgpu::BindGroup,
-        light_bind_group: &'a wgpu::BindGroup,
-    ) {
-        self.draw_mesh_instanced(mesh, material, 0..1, camera_bind_group, light_bind_group);
-    }
-
     fn draw_mesh_instanced(
         &mut self,
         mesh: &'b Mesh,
@@ -205,38 +549,9 @@ impl<'a, 'b> DrawModel<'b> for wgpu::RenderPass<'a>
         self.set_bind_group(2, light_bind_group, &[]);
         self.draw_indexed(0..mesh.num_elements, 0, instances);
     }
-
-    fn draw_model(
-        &mut self,
-        model: &'b Object,
-        camera_bind_group: &'b wgpu::BindGroup,
-        light_bind_group: &'a wgpu::BindGroup,
-    ) {
-        self.draw_model_instanced(model, 0..1, camera_bind_group, light_bind_group);
-    }
-
-    fn draw_model_instanced(
-        &mut self,
-        model: &'a Object,
-        instances: Range<u32>,
-        camera_bind_group: &'a wgpu::BindGroup,
-        light_bind_group: &'a wgpu::BindGroup,
-    ) {
-        for mesh in &model.meshes {
-            log::warn!("materials: {}", model.materials.len());
-            let material = &model.materials[mesh.material];
-            self.draw_mesh_instanced(mesh, material, instances.clone(), camera_bind_group, light_bind_group);
-        }
-    }
 }
 
 pub trait DrawLight<'a> {
-    fn draw_light_mesh(
-        &mut self,
-        mesh: &'a Mesh,
-        camera_bind_group: &'a wgpu::BindGroup,
-        light_bind_group: &'a wgpu::BindGroup,
-    );
     fn draw_light_mesh_instanced(
         &mut self,
         mesh: &'a Mesh,
@@ -244,12 +559,6 @@ pub trait DrawLight<'a> {
         camera_bind_group: &'a wgpu::BindGroup,
         light_bind_group: &'a wgpu::BindGroup,
     );
-    fn draw_light_model(
-        &mut self,
-        model: &'a Object,
-        camera_bind_group: &'a wgpu::BindGroup,
-        light_bind_group: &'a wgpu::BindGroup,
-    );
     fn draw_light_model_instanced(
         &mut self,
         model: &'a Object,
@@ -263,14 +572,6 @@ impl<'a, 'b> DrawLight<'b> for wgpu::RenderPass<'a>
 where
     'b: 'a
 {
-    fn draw_light_mesh(
-        &mut self,
-        mesh: &'a Mesh,
-        camera_bind_group: &'a wgpu::BindGroup,
-        light_bind_group: &'a wgpu::BindGroup,
-    ) {
-        self.draw_light_mesh_instanced(mesh, 0..1, camera_bind_group, light_bind_group);
-    }
     fn draw_light_mesh_instanced(
         &mut self,
         mesh: &'a Mesh,
@@ -284,30 +585,169 @@ where
         self.set_bind_group(1, light_bind_group, &[]);
         self.draw_indexed(0..mesh.num_elements, 0, instances);
     }
-    fn draw_light_model(
+    fn draw_light_model_instanced(
         &mut self,
         model: &'a Object,
+        instances: Range<u32>,
         camera_bind_group: &'a wgpu::BindGroup,
         light_bind_group: &'a wgpu::BindGroup,
     ) {
-        self.draw_light_model_instanced(model, 0..1, camera_bind_group, light_bind_group);
+        for mesh in &model.meshes {
+            self.draw_light_mesh_instanced(mesh, instances.clone(), camera_bind_group, light_bind_group);
+        }
     }
-    fn draw_light_model_instanced(
+}
+
+/// Drawing only the geometry of a model, without any material. This is what the shadow
+/// passes need, as they only care about the depth.
+pub trait DrawShadow<'a> {
+    fn draw_shadow_mesh_instanced(
+        &mut self,
+        mesh: &'a Mesh,
+        instances: Range<u32>,
+    );
+    fn draw_shadow_model_instanced(
         &mut self,
         model: &'a Object,
         instances: Range<u32>,
-        camera_bind_group: &'a wgpu::BindGroup,
-        light_bind_group: &'a wgpu::BindGroup,
+    );
+}
+
+impl<'a, 'b> DrawShadow<'b> for wgpu::RenderPass<'a>
+where
+    'b: 'a
+{
+    fn draw_shadow_mesh_instanced(
+        &mut self,
+        mesh: &'b Mesh,
+        instances: Range<u32>,
+    ) {
+        self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+        self.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+        self.draw_indexed(0..mesh.num_elements, 0, instances);
+    }
+    fn draw_shadow_model_instanced(
+        &mut self,
+        model: &'b Object,
+        instances: Range<u32>,
     ) {
         for mesh in &model.meshes {
-            self.draw_light_mesh_instanced(mesh, instances.clone(), camera_bind_group, light_bind_group);
+            self.draw_shadow_mesh_instanced(mesh, instances.clone());
         }
     }
 }
 
-struct Scene {
-    name: String,
-    objects: Vec<Object>,
-    instances: HashMap<Object, Vec<Instance>>,
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Deg, InnerSpace, Matrix3, Matrix4, Quaternion, Rotation3, Vector3, Vector4};
+
+    const EPSILON: f32 = 1e-4;
+
+    fn assert_close(a: Vector3<f32>, b: Vector3<f32>) {
+        assert!((a - b).magnitude() < EPSILON, "{:?} != {:?}", a, b);
+    }
+
+    fn instance(scale: Vector3<f32>) -> Instance {
+        Instance {
+            position: Vector3::new(1.0, -2.0, 3.0),
+            rotation: Quaternion::from_axis_angle(Vector3::new(1.0, 2.0, -1.0).normalize(), Deg(50.0)),
+            scale,
+        }
+    }
+
+    // scale, then rotate, then move, one step after the other
+    fn reference_point(instance: &Instance, point: Vector3<f32>) -> Vector3<f32> {
+        let scaled = Vector3::new(point.x * instance.scale.x, point.y * instance.scale.y, point.z * instance.scale.z);
+        instance.rotation * scaled + instance.position
+    }
+
+    // normals are scaled by the inverse scale and then rotated
+    fn reference_normal(instance: &Instance, normal: Vector3<f32>) -> Vector3<f32> {
+        let scaled = Vector3::new(normal.x / instance.scale.x, normal.y / instance.scale.y, normal.z / instance.scale.z);
+        instance.rotation * scaled
+    }
+
+    fn gpu_normal_matrix(instance: &GPUInstance) -> Matrix3<f32> {
+        let [x, y, z] = instance.normal.map(|column| Vector4::from(column).truncate());
+        Matrix3::from_cols(x, y, z)
+    }
+
+    #[test]
+    fn non_uniform_scale_moves_the_vertices() {
+        let instance = instance(Vector3::new(2.0, 0.5, 3.0));
+        let gpu = GPUInstance::from_matrix(instance.to_matrix());
+        let model = Matrix4::from(gpu.model);
+        for point in [Vector3::new(1.0, 0.0, 0.0), Vector3::new(0.0, 1.0, 0.0), Vector3::new(0.3, -0.7, 0.9)] {
+            assert_close((model * point.extend(1.0)).truncate(), reference_point(&instance, point));
+        }
+    }
+
+    #[test]
+    fn normals_stay_perpendicular() {
+        let instance = instance(Vector3::new(2.0, 0.5, 3.0));
+        let transform = instance.to_matrix();
+        let normal_matrix = gpu_normal_matrix(&GPUInstance::from_matrix(transform));
+        // a slanted surface, with the normal and two tangents in it
+        let normal = Vector3::new(1.0, 1.0, 1.0).normalize();
+        let tangents = [Vector3::new(1.0, -1.0, 0.0), Vector3::new(0.0, 1.0, -1.0)];
+        let world_normal = normal_matrix * normal;
+        assert_close(world_normal, reference_normal(&instance, normal));
+        for tangent in tangents {
+            let world_tangent = (transform * tangent.extend(0.0)).truncate();
+            assert!(world_normal.dot(world_tangent).abs() < EPSILON);
+            // the scale alone would not keep them perpendicular
+            assert!((transform * normal.extend(0.0)).truncate().dot(world_tangent).abs() > 0.1);
+        }
+    }
+
+    #[test]
+    fn rotation_is_its_own_normal_matrix() {
+        let instance = instance(Vector3::new(1.0, 1.0, 1.0));
+        let gpu = GPUInstance::from_matrix(instance.to_matrix());
+        let rotation = Matrix3::from(instance.rotation);
+        let normal_matrix = gpu_normal_matrix(&gpu);
+        for axis in 0..3 {
+            assert_close(normal_matrix[axis], rotation[axis]);
+        }
+        // the padding is zero, so the columns can be read as vec4 as well
+        assert!(gpu.normal.iter().all(|column| column[3] == 0.0));
+    }
+
+    #[test]
+    fn uniform_scale_keeps_the_normal_direction() {
+        let instance = instance(Vector3::new(4.0, 4.0, 4.0));
+        let normal_matrix = gpu_normal_matrix(&GPUInstance::from_matrix(instance.to_matrix()));
+        let normal = Vector3::new(0.0, 0.0, 1.0);
+        assert_close((normal_matrix * normal).normalize(), instance.rotation * normal);
+    }
+
+    #[test]
+    fn instance_layout_matches_the_shader() {
+        // a mat4x4 and a mat3x3 with vec4 columns, the stride of the array in animation.wgsl
+        assert_eq!(std::mem::size_of::<GPUInstance>(), 112);
+        let attributes = GPUInstance::desc().attributes;
+        assert_eq!(attributes.iter().map(|a| a.shader_location).collect::<Vec<_>>(), [5, 6, 7, 8, 9, 10, 11]);
+        assert_eq!(attributes[4].offset, 64);
+    }
 
+    #[test]
+    fn material_uniform_matches_the_shader() {
+        // the vec3s of struct Material in shader.wgsl are 16 byte aligned, so specular and
+        // emissive are each followed by padding where the scalars fill it for the others
+        assert_eq!(std::mem::offset_of!(MaterialUniform, specular), 32);
+        assert_eq!(std::mem::offset_of!(MaterialUniform, emissive), 48);
+        assert_eq!(std::mem::size_of::<MaterialUniform>(), 64);
+    }
+
+    #[test]
+    fn pbr_material_uniform_matches_the_shader() {
+        // metallic fills the rest of the emissive vec3 like in struct PbrMaterial in pbr.wgsl,
+        // and the struct is padded to the 16 byte alignment of base_color
+        assert_eq!(std::mem::offset_of!(PbrMaterialUniform, emissive), 16);
+        assert_eq!(std::mem::offset_of!(PbrMaterialUniform, metallic), 28);
+        assert_eq!(std::mem::offset_of!(PbrMaterialUniform, roughness), 32);
+        assert_eq!(std::mem::offset_of!(PbrMaterialUniform, occlusion_strength), 36);
+        assert_eq!(std::mem::size_of::<PbrMaterialUniform>(), 48);
+    }
 }
@@ -3,11 +3,104 @@ use winit::event::*;
 use winit::dpi::PhysicalPosition;
 use instant::Duration;
 use winit::window::WindowId;
-use std::f32::consts::FRAC_PI_2;
-use bytemuck;
+use std::f32::consts::{FRAC_PI_2, PI};
+use crate::bounds::{BoundingSphere, Ray};
+use crate::input::{Action, InputMap};
 use crate::wgpu_utils::*;
 
 const SAFE_FRAC_PI_2: f32 = FRAC_PI_2 - 0.0001;
+// the orbiting camera does not get closer to the target than this
+const MIN_ORBIT_DISTANCE: f32 = 0.1;
+// how far the target moves per pixel the mouse is dragged, relative to the distance to it
+const PAN_SPEED: f32 = 0.002;
+// how many seconds the switch between perspective and orthographic projection takes
+const PROJECTION_TRANSITION_TIME: f32 = 0.4;
+// how far the camera turns per pixel the mouse moves at a sensitivity of 1
+const RADIANS_PER_PIXEL: f32 = 0.01;
+// how far the scroll wheel moves the camera per pixel at a sensitivity of 1
+const ZOOM_PER_PIXEL: f32 = 0.06;
+
+/// How the camera reacts to the controls
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CameraMode {
+    /// The camera moves freely and turns around itself
+    Fly,
+    /// The camera circles around a target point and always looks at it
+    Orbit,
+}
+
+/// How the view is projected onto the screen
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ProjectionKind {
+    Perspective,
+    /// Parallel projection, things keep their size no matter how far away they are
+    Orthographic,
+}
+
+/// Views along the axes of the world, for looking at the scene like at a technical drawing
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ViewPreset {
+    /// Looking down, -z is up on the screen
+    Top,
+    /// Looking along -z
+    Front,
+    /// Looking along -x
+    Side,
+}
+
+impl ViewPreset {
+    /// yaw and pitch of the view
+    fn angles(self) -> (Rad<f32>, Rad<f32>) {
+        match self {
+            ViewPreset::Top => (Rad(-FRAC_PI_2), Rad(-SAFE_FRAC_PI_2)),
+            ViewPreset::Front => (Rad(-FRAC_PI_2), Rad(0.0)),
+            ViewPreset::Side => (Rad(PI), Rad(0.0)),
+        }
+    }
+}
+
+/// How the distance from the camera is mapped to the depth buffer
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DepthMode {
+    /// The near plane is at depth 0 and the far plane at 1
+    Standard,
+    /// The near plane is at depth 1 and the depth goes towards 0 at infinity, there is no
+    /// far plane. Floats are most precise close to 0, which makes up for the precision
+    /// the perspective division takes away from far fragments, so they do not z-fight.
+    /// Orthographic views keep their far plane.
+    ReverseInfinite,
+}
+
+impl DepthMode {
+    /// The depth the depth buffer is cleared to, it is behind everything
+    pub fn clear_value(self) -> f32 {
+        match self {
+            DepthMode::Standard => 1.0,
+            DepthMode::ReverseInfinite => 0.0,
+        }
+    }
+
+    /// The depth of the near plane
+    pub fn near_depth(self) -> f32 {
+        1.0 - self.clear_value()
+    }
+
+    /// The depth test that lets fragments in front of the stored depth through
+    pub fn compare(self) -> wgpu::CompareFunction {
+        match self {
+            DepthMode::Standard => wgpu::CompareFunction::Less,
+            DepthMode::ReverseInfinite => wgpu::CompareFunction::Greater,
+        }
+    }
+
+    /// Like `compare`, but fragments at the stored depth get through as well
+    pub fn compare_or_equal(self) -> wgpu::CompareFunction {
+        match self {
+            DepthMode::Standard => wgpu::CompareFunction::LessEqual,
+            DepthMode::ReverseInfinite => wgpu::CompareFunction::GreaterEqual,
+        }
+    }
+}
 
 #[rustfmt::skip]
 pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
@@ -17,6 +110,29 @@ pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
     0.0, 0.0, 0.5, 1.0
 );
 
+// turns a depth of 0 to 1 in wgpu clip space around
+#[rustfmt::skip]
+const REVERSE_Z_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
+    1.0, 0.0, 0.0, 0.0,
+    0.0, 1.0, 0.0, 0.0,
+    0.0, 0.0, -1.0, 0.0,
+    0.0, 0.0, 1.0, 1.0
+);
+
+/// A perspective projection straight into wgpu clip space that puts the near plane at
+/// depth 1 and infinity at depth 0
+pub fn reverse_infinite_perspective<F: Into<Rad<f32>>>(field_of_view: F, aspect: f32, znear: f32) -> Matrix4<f32> {
+    let f = 1.0 / (field_of_view.into().0 / 2.0).tan();
+    #[rustfmt::skip]
+    let matrix = Matrix4::new(
+        f / aspect, 0.0, 0.0, 0.0,
+        0.0, f, 0.0, 0.0,
+        0.0, 0.0, 0.0, -1.0,
+        0.0, 0.0, znear, 0.0,
+    );
+    matrix
+}
+
 /// This is the location and viw-direction of the observer It needs to change,
 /// when the observer is moved or rotated
 #[derive(Debug)]
@@ -28,9 +144,21 @@ pub struct Camera {
     pub view: ViewMatrix,
     pub projection: Projection,
     pub controlls: CameraControlls,
+    mode: CameraMode,
+    // the point an orbiting camera circles around and how far away from it the camera is
+    target: Point3<f32>,
+    distance: f32,
+    // the camera speeds up and slows down instead of starting and stopping at once
+    velocity: Vector3<f32>,
+    // the mouse turns these and the view follows them smoothly
+    target_yaw: Rad<f32>,
+    target_pitch: Rad<f32>,
+    yaw_velocity: f32,
+    pitch_velocity: f32,
 }
 
 impl Camera {
+    #[allow(clippy::too_many_arguments)]
     pub fn new<V: Into<Point3<f32>>, Y: Into<Rad<f32>>, P: Into<Rad<f32>>, F: Into<Rad<f32>>>(
         position: V,
         yaw: Y,
@@ -51,28 +179,134 @@ impl Camera {
             pitch: pitch.into(),
             projection: Projection::new(screen_width, screen_height, field_of_view, znear, zfar),
             view: ViewMatrix::new(),
-            uniform: CameraUniform::new(&device),
+            uniform: CameraUniform::new(device),
             controlls: CameraControlls::new(speed, sensitivity),
+            mode: CameraMode::Fly,
+            target: Point3::origin(),
+            distance: 10.0,
+            velocity: Vector3::zero(),
+            target_yaw: Rad(0.0),
+            target_pitch: Rad(0.0),
+            yaw_velocity: 0.0,
+            pitch_velocity: 0.0,
         };
-        out.update_gpu_state(&queue);
+        out.stop();
+        out.target = out.position + out.direction() * out.distance;
+        out.update_gpu_state(queue);
         out
     }
+
+    pub fn yaw(&self) -> Rad<f32> {
+        self.yaw
+    }
+
+    pub fn pitch(&self) -> Rad<f32> {
+        self.pitch
+    }
+
+    /// Move the camera to `position` and look into the direction given by yaw and pitch,
+    /// the pitch is limited to just short of straight up or down. An orbiting camera
+    /// orbits around the point in front of it afterwards.
+    pub fn set_view<V: Into<Point3<f32>>, Y: Into<Rad<f32>>, P: Into<Rad<f32>>>(&mut self, position: V, yaw: Y, pitch: P) {
+        self.position = position.into();
+        self.yaw = yaw.into();
+        self.pitch = Rad(pitch.into().0.clamp(-SAFE_FRAC_PI_2, SAFE_FRAC_PI_2));
+        self.target = self.position + self.direction() * self.distance;
+        self.stop();
+    }
+
+    /// Stop moving and turning, so that the camera stays where it was put
+    fn stop(&mut self) {
+        self.velocity = Vector3::zero();
+        self.target_yaw = self.yaw;
+        self.target_pitch = self.pitch;
+        self.yaw_velocity = 0.0;
+        self.pitch_velocity = 0.0;
+    }
+
+    pub fn mode(&self) -> CameraMode {
+        self.mode
+    }
+
+    /// Switch between flying and orbiting without changing the view, the camera starts
+    /// to orbit around the point in front of it at the last orbit distance.
+    pub fn set_mode(&mut self, mode: CameraMode) {
+        if mode == CameraMode::Orbit && self.mode != CameraMode::Orbit {
+            self.target = self.position + self.direction() * self.distance;
+        }
+        self.mode = mode;
+    }
+
+    /// Move the camera back (or forward) along its view direction until the sphere fills
+    /// the view. An orbiting camera orbits around the center of the sphere afterwards.
+    pub fn frame(&mut self, sphere: &BoundingSphere) {
+        let half_fov_y = self.projection.field_of_view.0 / 2.0;
+        let half_fov_x = (half_fov_y.tan() * self.projection.aspect).atan();
+        self.distance = (sphere.radius / half_fov_y.min(half_fov_x).sin()).max(MIN_ORBIT_DISTANCE);
+        self.target = sphere.center;
+        self.position = self.target - self.direction() * self.distance;
+        self.projection.focus(self.distance);
+    }
+
+    /// Switch between perspective and orthographic projection. The switch is blended over
+    /// a short time and keeps the size of things at the orbit distance (the distance of
+    /// the target of an orbiting camera).
+    pub fn set_projection(&mut self, kind: ProjectionKind) {
+        if kind == self.projection.kind() {
+            return;
+        }
+        match kind {
+            ProjectionKind::Orthographic => self.projection.focus(self.distance),
+            ProjectionKind::Perspective => {
+                // the orthographic extent may have been zoomed, move to where the
+                // perspective view shows the same
+                if self.mode == CameraMode::Fly {
+                    self.target = self.position + self.direction() * self.distance;
+                }
+                self.distance = self.projection.focus_distance().max(MIN_ORBIT_DISTANCE);
+                self.position = self.target - self.direction() * self.distance;
+            }
+        }
+        self.projection.kind = kind;
+    }
+
+    /// Look at the point in front of the camera (or at the target of an orbiting camera)
+    /// along one of the axes, from the same distance
+    pub fn look_along(&mut self, preset: ViewPreset) {
+        if self.mode == CameraMode::Fly {
+            self.target = self.position + self.direction() * self.distance;
+        }
+        (self.yaw, self.pitch) = preset.angles();
+        self.position = self.target - self.direction() * self.distance;
+        self.stop();
+    }
+
+    /// The direction the camera looks into
+    fn direction(&self) -> Vector3<f32> {
+        let (sin_pitch, cos_pitch) = self.pitch.0.sin_cos();
+        let (sin_yaw, cos_yaw) = self.yaw.0.sin_cos();
+        Vector3::new(cos_pitch * cos_yaw, sin_pitch, cos_pitch * sin_yaw).normalize()
+    }
+
     /// Compute the translation and rotation needed to make it appear as if the
     /// observer is at a given location
     pub fn compute_view_space_transform_matrix(&self) -> Matrix4<f32> {
-        let (sin_pitch, cos_pitch) = self.pitch.0.sin_cos();
-        let (sin_yaw, cos_yaw) = self.yaw.0.sin_cos();
-        Matrix4::look_to_rh(
-            self.position, 
-            Vector3::new(
-                cos_pitch * cos_yaw,
-                sin_pitch,
-                cos_pitch * sin_yaw,
-            ).normalize(),
-            Vector3::unit_y(),
-        )
+        Matrix4::look_to_rh(self.position, self.direction(), Vector3::unit_y())
     }
     
+    /// The ray from the camera through a point on the screen, given in pixels from the top
+    /// left corner of a screen of the given size. It starts on the near plane. A degenerate
+    /// projection has no ray.
+    pub fn cursor_ray(&self, x: f32, y: f32, screen_width: u32, screen_height: u32) -> Option<Ray> {
+        let ndc_x = 2.0 * x / screen_width as f32 - 1.0;
+        let ndc_y = 1.0 - 2.0 * y / screen_height as f32;
+        let to_world = Matrix4::from(self.view.view_proj).invert()?;
+        let near = to_world.transform_point(Point3::new(ndc_x, ndc_y, self.projection.depth_mode().near_depth()));
+        // the far plane may be at infinity, any depth between gives the direction
+        let beyond = to_world.transform_point(Point3::new(ndc_x, ndc_y, 0.5));
+        Some(Ray { origin: near, direction: (beyond - near).normalize() })
+    }
+
     pub fn update_gpu_state(&mut self, queue: &wgpu::Queue) {
         let view_transform = self.compute_view_space_transform_matrix();
         let projection_matrix = self.projection.compute_matrix();
@@ -83,47 +317,133 @@ impl Camera {
     pub fn update(&mut self, dt: Duration, queue: &wgpu::Queue) {
         let dt = dt.as_secs_f32();
 
-        // Process moving forward/backward/left/right/up/down
+        // Process moving forward/backward/left/right/up/down, the keys give the direction
+        // and the camera accelerates towards the top speed in that direction
         let (yaw_sin, yaw_cos) = self.yaw.0.sin_cos();
         let pitch_sin = self.pitch.0.sin();
         let forward = Vector3::new(yaw_cos, pitch_sin, yaw_sin).normalize();
         let right = Vector3::new(-yaw_sin, 0.0, yaw_cos).normalize();
-        self.position += forward * (self.controlls.amount_forward - self.controlls.amount_backward) * self.controlls.speed * dt;
-        self.position += right * (self.controlls.amount_right - self.controlls.amount_left) * self.controlls.speed * dt;
-        self.position.y += (self.controlls.amount_up - self.controlls.amount_down) * self.controlls.speed * dt;
+        let direction = forward * (self.controlls.amount_forward - self.controlls.amount_backward)
+            + right * (self.controlls.amount_right - self.controlls.amount_left)
+            + Vector3::unit_y() * (self.controlls.amount_up - self.controlls.amount_down);
+        let desired_velocity = if direction.is_zero() { direction } else { direction.normalize_to(self.controlls.max_speed) };
+        self.velocity = accelerate(self.velocity, desired_velocity, self.controlls.acceleration, dt);
+        let movement = self.velocity * dt;
 
         // move in and out (via the scroll wheel
-        let (pitch_sin, pitch_cos) = self.pitch.0.sin_cos();
-        let scrollward = Vector3::new(pitch_cos * yaw_cos, pitch_sin, pitch_cos * yaw_sin).normalize();
-        self.position += scrollward * self.controlls.scroll * self.controlls.speed * self.controlls.sensitivity * dt;
+        let scrollward = self.direction();
+        let zoom = self.controlls.scroll * self.controlls.sensitivity * ZOOM_PER_PIXEL;
         self.controlls.scroll = 0.0;
 
-        self.yaw += Rad(self.controlls.rotate_horizontal) * self.controlls.sensitivity * dt;
-        self.pitch += Rad(-self.controlls.rotate_vertical) * self.controlls.sensitivity * dt;
+        // moving closer does not make things bigger in an orthographic view, the scroll
+        // wheel shrinks the extent of the view instead
+        if self.projection.kind() == ProjectionKind::Orthographic {
+            let focus = (self.distance - zoom).max(MIN_ORBIT_DISTANCE);
+            let height = self.projection.orthographic_height() * focus / self.distance;
+            self.projection.set_orthographic_height(height);
+            if self.mode == CameraMode::Orbit {
+                self.distance = focus;
+            }
+        }
+        let orthographic = self.projection.kind() == ProjectionKind::Orthographic;
+
+        match self.mode {
+            CameraMode::Fly if orthographic => self.position += movement,
+            CameraMode::Fly => self.position += movement + scrollward * zoom,
+            CameraMode::Orbit => {
+                // the keys move the target around, the scroll wheel moves towards it
+                self.target += movement;
+                if !orthographic {
+                    self.distance = (self.distance - zoom).max(MIN_ORBIT_DISTANCE);
+                }
+                // dragging with the middle button moves the target in the view plane, so
+                // that the scene follows the mouse
+                let up = right.cross(scrollward);
+                let pan = up * self.controlls.pan_vertical - right * self.controlls.pan_horizontal;
+                self.target += pan * PAN_SPEED * self.distance;
+            }
+        }
+        self.controlls.pan_horizontal = 0.0;
+        self.controlls.pan_vertical = 0.0;
 
+        // the mouse turns the view it is heading for, the view follows without overshooting
+        let look = self.controlls.sensitivity * RADIANS_PER_PIXEL;
+        self.target_yaw += Rad(self.controlls.rotate_horizontal * look);
+        self.target_pitch += Rad(-self.controlls.rotate_vertical * look);
+        self.target_pitch = Rad(self.target_pitch.0.clamp(-SAFE_FRAC_PI_2, SAFE_FRAC_PI_2));
         self.controlls.rotate_horizontal = 0.0;
         self.controlls.rotate_vertical = 0.0;
 
-        if self.pitch < -Rad(SAFE_FRAC_PI_2) {
-            self.pitch = -Rad(SAFE_FRAC_PI_2);
-        } else if self.pitch > Rad(SAFE_FRAC_PI_2) {
-            self.pitch = Rad(SAFE_FRAC_PI_2);
+        let smoothing = self.controlls.look_smoothing;
+        self.yaw = Rad(smooth_damp(self.yaw.0, self.target_yaw.0, &mut self.yaw_velocity, smoothing, dt));
+        self.pitch = Rad(smooth_damp(self.pitch.0, self.target_pitch.0, &mut self.pitch_velocity, smoothing, dt));
+        self.pitch = Rad(self.pitch.0.clamp(-SAFE_FRAC_PI_2, SAFE_FRAC_PI_2));
+        // turning makes an orbiting camera circle around the target
+        if self.mode == CameraMode::Orbit {
+            self.position = self.target - self.direction() * self.distance;
         }
+        self.projection.update(dt);
+        self.update_gpu_state(queue);
+    }
+
+    /// Update the camera while something else moves it, like a camera path that is played.
+    /// The mouse movement in the meantime is dropped instead of being applied all at once
+    /// afterwards, and a switch of the projection still goes on.
+    pub fn update_without_input(&mut self, dt: Duration, queue: &wgpu::Queue) {
+        self.controlls.clear_deltas();
+        self.projection.update(dt.as_secs_f32());
         self.update_gpu_state(queue);
     }
 }
 
+/// Change the velocity towards the desired one, by at most `acceleration` per second
+fn accelerate(velocity: Vector3<f32>, desired: Vector3<f32>, acceleration: f32, dt: f32) -> Vector3<f32> {
+    let change = desired - velocity;
+    let max_change = acceleration * dt;
+    if change.magnitude() > max_change {
+        velocity + change.normalize_to(max_change)
+    } else {
+        desired
+    }
+}
+
+/// Move `current` towards `target` like a critically damped spring that takes about
+/// `smooth_time` seconds to get there, `velocity` carries over between the calls. The
+/// step is exact for any dt up to the approximation of the exponential (from Game
+/// Programming Gems 4, 1.10).
+fn smooth_damp(current: f32, target: f32, velocity: &mut f32, smooth_time: f32, dt: f32) -> f32 {
+    if smooth_time <= 0.0 {
+        *velocity = 0.0;
+        return target;
+    }
+    let omega = 2.0 / smooth_time;
+    let x = omega * dt;
+    let decay = 1.0 / (1.0 + x + 0.48 * x * x + 0.235 * x * x * x);
+    let offset = current - target;
+    let temp = (*velocity + omega * offset) * dt;
+    *velocity = (*velocity - omega * temp) * decay;
+    target + (offset + temp) * decay
+}
+
 /// After the world has been transformed into camera space,
 /// the coordinates of the model need to be altered in such
 /// way as to make the orthographic projection (build in to the gpu
 /// look like a perspective view of the world, for this a projection
 /// matrix distorts the coordinates of the vertices in view space
+///
+/// The projection can also be orthographic, then it shows a box of the world that is
+/// `orthographic_height` high and as wide as the aspect ratio makes it.
 #[derive(Debug)]
 pub struct Projection {
     aspect: f32,
     field_of_view: Rad<f32>,
     znear: f32,
     zfar: f32,
+    kind: ProjectionKind,
+    orthographic_height: f32,
+    // how far the switch to orthographic has gone, from 0 (perspective) to 1
+    orthographic_blend: f32,
+    depth_mode: DepthMode,
 }
 
 impl Projection {
@@ -139,14 +459,104 @@ impl Projection {
             field_of_view: field_of_view.into(),
             znear,
             zfar,
+            kind: ProjectionKind::Perspective,
+            orthographic_height: 10.0,
+            orthographic_blend: 0.0,
+            depth_mode: DepthMode::Standard,
         }
     }
     pub fn resize(&mut self, width: u32, height: u32) {
         self.aspect = width as f32 / height as f32;
     }
 
+    pub fn field_of_view(&self) -> Rad<f32> {
+        self.field_of_view
+    }
+
+    pub fn znear(&self) -> f32 {
+        self.znear
+    }
+
+    pub fn zfar(&self) -> f32 {
+        self.zfar
+    }
+
+    /// Change the field of view and the clip planes, the aspect ratio stays
+    pub fn set_perspective<F: Into<Rad<f32>>>(&mut self, field_of_view: F, znear: f32, zfar: f32) {
+        self.field_of_view = field_of_view.into();
+        self.znear = znear;
+        self.zfar = zfar;
+    }
+
+    pub fn depth_mode(&self) -> DepthMode {
+        self.depth_mode
+    }
+
+    /// The depth test of the pipelines has to match, `Renderer::set_depth_mode` changes both
+    pub fn set_depth_mode(&mut self, depth_mode: DepthMode) {
+        self.depth_mode = depth_mode;
+    }
+
+    /// The projection that is switched to, use `Camera::set_projection` to switch
+    pub fn kind(&self) -> ProjectionKind {
+        self.kind
+    }
+
+    /// How much of the world the orthographic view shows vertically
+    pub fn orthographic_height(&self) -> f32 {
+        self.orthographic_height
+    }
+
+    pub fn set_orthographic_height(&mut self, height: f32) {
+        self.orthographic_height = height.max(f32::EPSILON);
+    }
+
+    /// Make the orthographic view show things at `distance` as big as the perspective
+    /// view shows them
+    pub fn focus(&mut self, distance: f32) {
+        self.set_orthographic_height(2.0 * distance * (self.field_of_view.0 / 2.0).tan());
+    }
+
+    /// The distance at which the perspective view shows things as big as the orthographic
+    /// view does
+    pub fn focus_distance(&self) -> f32 {
+        self.orthographic_height / (2.0 * (self.field_of_view.0 / 2.0).tan())
+    }
+
+    /// Advance the switch between perspective and orthographic projection
+    pub fn update(&mut self, dt: f32) {
+        let step = dt / PROJECTION_TRANSITION_TIME;
+        self.orthographic_blend = match self.kind {
+            ProjectionKind::Perspective => (self.orthographic_blend - step).max(0.0),
+            ProjectionKind::Orthographic => (self.orthographic_blend + step).min(1.0),
+        };
+    }
+
     pub fn compute_matrix(&self) -> Matrix4<f32> {
-        OPENGL_TO_WGPU_MATRIX * perspective(self.field_of_view, self.aspect, self.znear, self.zfar)
+        let half_height = self.orthographic_height / 2.0;
+        let half_width = half_height * self.aspect;
+        let orthographic = OPENGL_TO_WGPU_MATRIX * ortho(-half_width, half_width, -half_height, half_height, self.znear, self.zfar);
+        let (perspective, orthographic) = match self.depth_mode {
+            DepthMode::Standard => (
+                OPENGL_TO_WGPU_MATRIX * perspective(self.field_of_view, self.aspect, self.znear, self.zfar),
+                orthographic,
+            ),
+            DepthMode::ReverseInfinite => (
+                reverse_infinite_perspective(self.field_of_view, self.aspect, self.znear),
+                REVERSE_Z_MATRIX * orthographic,
+            ),
+        };
+        // Blending the matrices keeps the size at the focus distance, both map a point there
+        // to the same place on the screen and the blend only scales its clip coordinates
+        let t = self.orthographic_blend;
+        let t = t * t * (3.0 - 2.0 * t);
+        if t == 0.0 {
+            perspective
+        } else if t == 1.0 {
+            orthographic
+        } else {
+            perspective * (1.0 - t) + orthographic * t
+        }
     }
 }
 
@@ -212,9 +622,20 @@ pub struct CameraControlls {
     amount_down: f32,
     rotate_horizontal: f32,
     rotate_vertical: f32,
+    pan_horizontal: f32,
+    pan_vertical: f32,
+    // the buttons for rotating and panning are held down
+    rotating: bool,
+    panning: bool,
     scroll: f32,
-    speed: f32,
-    sensitivity: f32,
+    /// the top speed of the camera in units per second
+    pub max_speed: f32,
+    /// how fast the camera gets up to speed and slows down again, in units per second²
+    pub acceleration: f32,
+    /// how much the camera turns and zooms per pixel the mouse moves or scrolls
+    pub sensitivity: f32,
+    /// about how many seconds the view takes to catch up with the mouse, 0 turns at once
+    pub look_smoothing: f32,
 }
 
 impl CameraControlls {
@@ -228,49 +649,58 @@ impl CameraControlls {
             amount_down: 0.0,
             rotate_horizontal: 0.0,
             rotate_vertical: 0.0,
+            pan_horizontal: 0.0,
+            pan_vertical: 0.0,
+            rotating: false,
+            panning: false,
             scroll: 0.0,
-            speed,
-            sensitivity
+            max_speed: speed,
+            // up to speed in a quarter of a second
+            acceleration: speed * 4.0,
+            sensitivity,
+            look_smoothing: 0.05,
         }
     }
-    pub fn proces_keyboard_input(&mut self, key: VirtualKeyCode, state: ElementState) -> bool {
-        let amount: f32 = if state == ElementState::Pressed {1.0} else {0.0};
-        match key {
-            VirtualKeyCode::R | VirtualKeyCode::Up => {
-                self.amount_forward = amount;
-                true
-            }
-            VirtualKeyCode::H | VirtualKeyCode::Down => {
-                self.amount_backward = amount;
-                true
-            }
-            VirtualKeyCode::S | VirtualKeyCode::Left => {
-                self.amount_left = amount;
-                true
-            }
-            VirtualKeyCode::T | VirtualKeyCode::Right => {
-                self.amount_right = amount;
-                true
-            }
-            VirtualKeyCode::Space => {
-                self.amount_up = amount;
-                true
-            }
-            VirtualKeyCode::LShift => {
-                self.amount_down = amount;
-                true 
-            }
-            _ => false,
+    /// Start (or stop) moving or dragging, the other actions are not for the camera
+    pub fn process_action(&mut self, action: Action, pressed: bool) -> bool {
+        let amount: f32 = if pressed {1.0} else {0.0};
+        match action {
+            Action::MoveForward => self.amount_forward = amount,
+            Action::MoveBackward => self.amount_backward = amount,
+            Action::MoveLeft => self.amount_left = amount,
+            Action::MoveRight => self.amount_right = amount,
+            Action::MoveUp => self.amount_up = amount,
+            Action::MoveDown => self.amount_down = amount,
+            Action::Rotate => self.rotating = pressed,
+            Action::Pan => self.panning = pressed,
+            _ => return false,
         }
+        true
     }
 
+    /// The deltas add up until the next update consumes them, there can be several mouse
+    /// events per frame or none at all
     pub fn process_mouse_movement(&mut self, mouse_dx: f64, mouse_dy: f64) {
-        self.rotate_horizontal = mouse_dx as f32;
-        self.rotate_vertical = mouse_dy as f32;
+        self.rotate_horizontal += mouse_dx as f32;
+        self.rotate_vertical += mouse_dy as f32;
     }
     
+    pub fn process_mouse_pan(&mut self, mouse_dx: f64, mouse_dy: f64) {
+        self.pan_horizontal += mouse_dx as f32;
+        self.pan_vertical += mouse_dy as f32;
+    }
+
+    /// Forget the mouse movement and the scrolling that no update consumed yet
+    pub fn clear_deltas(&mut self) {
+        self.rotate_horizontal = 0.0;
+        self.rotate_vertical = 0.0;
+        self.pan_horizontal = 0.0;
+        self.pan_vertical = 0.0;
+        self.scroll = 0.0;
+    }
+
     pub fn process_scroll(&mut self, delta: &MouseScrollDelta) {
-        self.scroll = -match delta {
+        self.scroll -= match delta {
             MouseScrollDelta::LineDelta(_, scroll) => scroll * 100.0,
             MouseScrollDelta::PixelDelta(PhysicalPosition {
                 y: scroll,
@@ -279,30 +709,199 @@ impl CameraControlls {
         };
     }
 
-    pub fn process_event(&mut self, ievent: &Event<()>, mouse_pressed: bool, event_window_id: WindowId) -> bool {
+    /// Handle the mouse movement and the scroll wheel and the actions the input map finds
+    /// in the window events
+    pub fn process_event(&mut self, ievent: &Event<()>, input: &InputMap, event_window_id: WindowId) -> bool {
         match ievent {
-            Event::DeviceEvent {event: DeviceEvent::MouseMotion { delta, }, .. } =>
-                if mouse_pressed {
-                    self.process_mouse_movement(delta.0, delta.1);
-                    true
-                } else {
-                    false
-                }
+            Event::DeviceEvent {event: DeviceEvent::MouseMotion { delta, }, .. } if self.rotating => {
+                self.process_mouse_movement(delta.0, delta.1);
+                true
+            }
+            Event::DeviceEvent {event: DeviceEvent::MouseMotion { delta, }, .. } if self.panning => {
+                self.process_mouse_pan(delta.0, delta.1);
+                true
+            }
             Event::WindowEvent { window_id, event } if *window_id == event_window_id => {
                 match event {
-                    WindowEvent::KeyboardInput { input: KeyboardInput {state, virtual_keycode: Some(key), .. }, ..} => {
-                        self.proces_keyboard_input(*key, *state)
-                    }
                     WindowEvent::MouseWheel {delta, ..} => {
                         self.process_scroll(delta);
                         true
                     }
-                    _ => false
+                    _ => {
+                        let mut handled = false;
+                        for (action, pressed) in input.actions(event) {
+                            handled |= self.process_action(action, pressed);
+                        }
+                        handled
+                    }
                 }
             }
             _ => false
         }
     }
-    
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: f32 = 1e-4;
+
+    // where a point in view space ends up on the screen, in normalized device coordinates
+    fn project(projection: &Projection, point: Point3<f32>) -> Point3<f32> {
+        projection.compute_matrix().transform_point(point)
+    }
+
+    #[test]
+    fn switching_keeps_the_size_at_the_focus_distance() {
+        let mut projection = Projection::new(800, 600, Deg(45.0), 0.1, 100.0);
+        projection.focus(7.0);
+        let point = Point3::new(1.5, -2.0, -7.0);
+        let expected = project(&projection, point);
+        for blend in [0.25, 0.5, 0.9, 1.0] {
+            projection.orthographic_blend = blend;
+            let projected = project(&projection, point);
+            assert!((projected.x - expected.x).abs() < EPSILON, "{:?} != {:?}", projected, expected);
+            assert!((projected.y - expected.y).abs() < EPSILON, "{:?} != {:?}", projected, expected);
+        }
+        // further away things do not shrink in the orthographic view
+        let far = project(&projection, Point3::new(1.5, -2.0, -50.0));
+        assert!((far.x - expected.x).abs() < EPSILON);
+        assert!((projection.focus_distance() - 7.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn orthographic_height_survives_resize() {
+        let mut projection = Projection::new(800, 600, Deg(45.0), 0.1, 100.0);
+        projection.set_orthographic_height(4.0);
+        projection.orthographic_blend = 1.0;
+        projection.resize(300, 900);
+        assert_eq!(projection.orthographic_height(), 4.0);
+        // the top of the view is 2 above the center, the sides are as far as the aspect says
+        let corner = project(&projection, Point3::new(2.0 / 3.0, 2.0, -10.0));
+        assert!((corner.x - 1.0).abs() < EPSILON && (corner.y - 1.0).abs() < EPSILON, "{:?}", corner);
+    }
+
+    // the depth of a point straight ahead at the given distance
+    fn depth(projection: &Projection, distance: f32) -> f32 {
+        project(projection, Point3::new(0.0, 0.0, -distance)).z
+    }
+
+    #[test]
+    fn standard_depth_goes_from_near_to_far() {
+        let projection = Projection::new(800, 600, Deg(45.0), 0.1, 100.0);
+        assert!(depth(&projection, 0.1).abs() < EPSILON);
+        assert!((depth(&projection, 100.0) - 1.0).abs() < EPSILON);
+        assert!(depth(&projection, 200.0) > 1.0);
+    }
+
+    #[test]
+    fn reverse_depth_goes_from_near_to_infinity() {
+        let mut projection = Projection::new(800, 600, Deg(45.0), 0.1, 100.0);
+        projection.set_depth_mode(DepthMode::ReverseInfinite);
+        assert!((depth(&projection, 0.1) - 1.0).abs() < EPSILON);
+        assert!((depth(&projection, 0.2) - 0.5).abs() < EPSILON);
+        // nothing is clipped at the far plane, further away is always closer to 0
+        let far = [100.0, 1.0e3, 1.0e5, 1.0e7].map(|distance| depth(&projection, distance));
+        assert!(far.iter().all(|&depth| depth > 0.0));
+        assert!(far.windows(2).all(|pair| pair[0] > pair[1]));
+        // behind the near plane is outside of the clip space
+        assert!(depth(&projection, 0.05) > 1.0);
+        // the screen position does not depend on the depth mode
+        let point = Point3::new(1.0, 2.0, -5.0);
+        let reverse = project(&projection, point);
+        projection.set_depth_mode(DepthMode::Standard);
+        let standard = project(&projection, point);
+        assert!((reverse.x - standard.x).abs() < EPSILON && (reverse.y - standard.y).abs() < EPSILON);
+    }
+
+    #[test]
+    fn reverse_orthographic_depth_keeps_the_far_plane() {
+        let mut projection = Projection::new(800, 600, Deg(45.0), 0.1, 100.0);
+        projection.set_depth_mode(DepthMode::ReverseInfinite);
+        projection.orthographic_blend = 1.0;
+        assert!((depth(&projection, 0.1) - 1.0).abs() < EPSILON);
+        assert!(depth(&projection, 100.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn depth_test_matches_the_mode() {
+        assert_eq!(DepthMode::Standard.clear_value(), 1.0);
+        assert_eq!(DepthMode::Standard.compare(), wgpu::CompareFunction::Less);
+        assert_eq!(DepthMode::ReverseInfinite.clear_value(), 0.0);
+        assert_eq!(DepthMode::ReverseInfinite.near_depth(), 1.0);
+        assert_eq!(DepthMode::ReverseInfinite.compare(), wgpu::CompareFunction::Greater);
+        assert_eq!(DepthMode::ReverseInfinite.compare_or_equal(), wgpu::CompareFunction::GreaterEqual);
+    }
+
+    #[test]
+    fn velocity_is_limited_by_the_acceleration() {
+        let desired = Vector3::new(4.0, 0.0, 0.0);
+        let velocity = accelerate(Vector3::zero(), desired, 8.0, 0.25);
+        assert!((velocity - Vector3::new(2.0, 0.0, 0.0)).magnitude() < EPSILON);
+        // close enough gets there exactly
+        assert_eq!(accelerate(velocity, desired, 8.0, 1.0), desired);
+        // slowing down works the same way
+        let velocity = accelerate(desired, Vector3::zero(), 8.0, 0.125);
+        assert!((velocity - Vector3::new(3.0, 0.0, 0.0)).magnitude() < EPSILON);
+    }
+
+    #[test]
+    fn look_smoothing_settles_without_overshooting() {
+        let (mut angle, mut velocity) = (0.0, 0.0);
+        let mut previous = angle;
+        for _ in 0..100 {
+            angle = smooth_damp(angle, 1.0, &mut velocity, 0.1, 1.0 / 60.0);
+            assert!(angle >= previous && angle <= 1.0, "{} after {}", angle, previous);
+            previous = angle;
+        }
+        assert!((angle - 1.0).abs() < 1e-3);
+        // without smoothing the target is reached at once
+        assert_eq!(smooth_damp(0.0, 1.0, &mut velocity, 0.0, 1.0 / 60.0), 1.0);
+    }
+
+    #[test]
+    fn look_smoothing_does_not_depend_on_the_frame_rate() {
+        let run = |fps: u32| {
+            let (mut angle, mut velocity) = (0.0, 0.0);
+            for _ in 0..fps / 10 {
+                angle = smooth_damp(angle, 1.0, &mut velocity, 0.2, 1.0 / fps as f32);
+            }
+            angle
+        };
+        // a tenth of a second at 30 and at 240 frames per second
+        assert!((run(30) - run(240)).abs() < 0.01, "{} != {}", run(30), run(240));
+    }
+
+    #[test]
+    fn mouse_deltas_add_up_between_updates() {
+        let mut controlls = CameraControlls::new(4.0, 0.4);
+        controlls.process_mouse_movement(3.0, -1.0);
+        controlls.process_mouse_movement(2.0, 4.0);
+        controlls.process_mouse_pan(1.0, 1.0);
+        controlls.process_mouse_pan(-3.0, 0.5);
+        controlls.process_scroll(&MouseScrollDelta::LineDelta(0.0, 1.0));
+        controlls.process_scroll(&MouseScrollDelta::PixelDelta(PhysicalPosition { x: 0.0, y: 20.0 }));
+        assert_eq!((controlls.rotate_horizontal, controlls.rotate_vertical), (5.0, 3.0));
+        assert_eq!((controlls.pan_horizontal, controlls.pan_vertical), (-2.0, 1.5));
+        assert_eq!(controlls.scroll, -120.0);
+        controlls.clear_deltas();
+        assert_eq!((controlls.rotate_horizontal, controlls.rotate_vertical), (0.0, 0.0));
+        assert_eq!((controlls.pan_horizontal, controlls.pan_vertical), (0.0, 0.0));
+        assert_eq!(controlls.scroll, 0.0);
+    }
+
+    #[test]
+    fn switch_is_blended_over_time() {
+        let mut projection = Projection::new(800, 600, Deg(45.0), 0.1, 100.0);
+        projection.kind = ProjectionKind::Orthographic;
+        projection.update(PROJECTION_TRANSITION_TIME / 2.0);
+        assert!((projection.orthographic_blend - 0.5).abs() < EPSILON);
+        projection.update(PROJECTION_TRANSITION_TIME);
+        assert_eq!(projection.orthographic_blend, 1.0);
+        projection.kind = ProjectionKind::Perspective;
+        projection.update(PROJECTION_TRANSITION_TIME * 2.0);
+        assert_eq!(projection.orthographic_blend, 0.0);
+    }
+}
@@ -0,0 +1,115 @@
+// Physically based shading with the metallic-roughness model (Cook-Torrance with the GGX
+// distribution), like glTF and the Principled BSDF of Blender.
+// The vertex shader and the lighting helpers are in common.wgsl
+
+@group(0) @binding(0)
+var t_base_color: texture_2d<f32>;
+@group(0) @binding(1)
+var s_base_color: sampler;
+@group(0) @binding(2)
+var t_normal: texture_2d<f32>;
+@group(0) @binding(3)
+var s_normal: sampler;
+// metallic is read from the blue and roughness from the green channel, so a packed glTF
+// texture can be bound to both, grayscale textures work as well
+@group(0) @binding(4)
+var t_metallic: texture_2d<f32>;
+@group(0) @binding(5)
+var s_metallic: sampler;
+@group(0) @binding(6)
+var t_roughness: texture_2d<f32>;
+@group(0) @binding(7)
+var s_roughness: sampler;
+// the occlusion is read from the red channel
+@group(0) @binding(8)
+var t_occlusion: texture_2d<f32>;
+@group(0) @binding(9)
+var s_occlusion: sampler;
+@group(0) @binding(10)
+var t_emissive: texture_2d<f32>;
+@group(0) @binding(11)
+var s_emissive: sampler;
+
+// the factors are multiplied with the values from the textures
+struct PbrMaterial {
+    base_color: vec4<f32>,
+    emissive: vec3<f32>,
+    metallic: f32,
+    roughness: f32,
+    occlusion_strength: f32,
+}
+@group(0) @binding(12)
+var<uniform> material: PbrMaterial;
+
+const PI: f32 = 3.14159265359;
+
+// the GGX / Trowbridge-Reitz normal distribution
+fn distribution_ggx(n_dot_h: f32, roughness: f32) -> f32 {
+    let a = roughness * roughness;
+    let a2 = a * a;
+    let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / (PI * d * d);
+}
+
+// Smith's geometry term with the Schlick-GGX approximation for both directions
+fn geometry_smith(n_dot_v: f32, n_dot_l: f32, roughness: f32) -> f32 {
+    let r = roughness + 1.0;
+    let k = r * r / 8.0;
+    let g_v = n_dot_v / (n_dot_v * (1.0 - k) + k);
+    let g_l = n_dot_l / (n_dot_l * (1.0 - k) + k);
+    return g_v * g_l;
+}
+
+fn fresnel_schlick(cos_theta: f32, f0: vec3<f32>) -> vec3<f32> {
+    return f0 + (1.0 - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let base_color = textureSample(t_base_color, s_base_color, in.tex_coords) * material.base_color;
+    let object_normal = textureSample(t_normal, s_normal, in.tex_coords);
+    let metallic = textureSample(t_metallic, s_metallic, in.tex_coords).b * material.metallic;
+    // very small roughness values make the highlights of point lights vanish
+    let roughness = clamp(textureSample(t_roughness, s_roughness, in.tex_coords).g * material.roughness, 0.04, 1.0);
+    let occlusion = mix(1.0, textureSample(t_occlusion, s_occlusion, in.tex_coords).r, material.occlusion_strength);
+    let emissive = textureSample(t_emissive, s_emissive, in.tex_coords).rgb * material.emissive;
+
+    let tangent_matrix = mat3x3<f32>(
+        normalize(in.world_tangent),
+        normalize(in.world_bitangent),
+        normalize(in.world_normal),
+    );
+    let n = normalize(tangent_matrix * (object_normal.xyz * 2.0 - 1.0));
+    let v = normalize(observer.position.xyz - in.position);
+    let n_dot_v = max(dot(n, v), 0.0001);
+
+    // dielectrics reflect about 4% at normal incidence, metals reflect their color
+    let f0 = mix(vec3<f32>(0.04), base_color.rgb, metallic);
+
+    var radiance_out = vec3<f32>(0.0);
+    var ambient_color = vec3<f32>(0.0);
+    for (var i = 0u; i < lights.count; i += 1u) {
+        let light = lights.lights[i];
+        let contribution = light_contribution(light, in.position);
+        let l = contribution.direction;
+        let h = normalize(v + l);
+        let n_dot_l = max(dot(n, l), 0.0);
+        let n_dot_h = max(dot(n, h), 0.0);
+        // the light intensities are tuned for the Phong shader, where a white surface
+        // reflects intensity * N·L, so the 1/π of the Lambertian BRDF is taken out again
+        let radiance = light.color * contribution.strength * shadow_factor(light, in.position) * PI;
+
+        let f = fresnel_schlick(max(dot(h, v), 0.0), f0);
+        let specular = distribution_ggx(n_dot_h, roughness) * geometry_smith(n_dot_v, n_dot_l, roughness) * f
+            / (4.0 * n_dot_v * n_dot_l + 0.0001);
+        // whatever is not reflected is refracted and scattered, metals absorb it
+        let k_d = (vec3<f32>(1.0) - f) * (1.0 - metallic);
+        radiance_out += (k_d * base_color.rgb / PI + specular) * radiance * n_dot_l;
+
+        let ambient_strength = 0.001;
+        ambient_color += light.color * ambient_strength;
+    }
+
+    let result = radiance_out + ambient_color * base_color.rgb * occlusion + emissive;
+    return vec4<f32>(result, base_color.a);
+}
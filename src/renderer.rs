@@ -0,0 +1,842 @@
+use cgmath::*;
+use crate::animation::InstanceAnimation;
+use crate::bounds::Frustum;
+use crate::layout::{self, Layout};
+use crate::camera_path::Flythrough;
+use crate::model::{self, GPUVertex, DrawModel, DrawLight, Instance, GPUInstance};
+use crate::observer::{Camera, DepthMode};
+use crate::scene_file::{CameraDescription, InstancesDescription, LightDescription, ModelDescription, NodeDescription, SceneDescription};
+use crate::wgpu_utils::create_render_pipeline;
+use crate::{light, resources, scene, shadow, texture, tonemap};
+
+// where the camera path for fly-throughs is saved and loaded
+const CAMERA_PATH_FILE: &str = "camera_path.ron";
+// the anisotropic filtering the textures are loaded with until it is changed
+const DEFAULT_TEXTURE_ANISOTROPY: Option<u16> = Some(16);
+
+/// What was clicked on
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Selection {
+    Node(scene::NodeId),
+    /// One of the animated instances. It is placed by the layout, so it is moved through
+    /// the node the animated instances belong to.
+    Animated(u32),
+}
+
+/// The Renderer owns everything that is needed to draw the scene, but it does not know
+/// where the scene is drawn to. The windowed application hands it the view of the current
+/// surface texture, the headless renderer hands it the view of an offscreen texture.
+pub struct Renderer {
+    pub device: wgpu::Device,
+    pub queue: wgpu::Queue,
+    render_pipeline_layout: wgpu::PipelineLayout,
+    render_pipeline: wgpu::RenderPipeline,
+    pbr_pipeline_layout: wgpu::PipelineLayout,
+    pbr_render_pipeline: wgpu::RenderPipeline,
+    light_pipeline_layout: wgpu::PipelineLayout,
+    light_render_pipeline: wgpu::RenderPipeline,
+    highlight_pipeline_layout: wgpu::PipelineLayout,
+    highlight_pipeline: wgpu::RenderPipeline,
+    material_layouts: model::MaterialLayouts,
+    size: (u32, u32),
+    sample_count: u32,
+    supported_sample_counts: Vec<u32>,
+    // the multisampled color target that gets resolved into the output, if multisampling is on
+    msaa_view: Option<wgpu::TextureView>,
+    // the scene is drawn into its HDR target and then mapped into the output
+    pub tone_mapping: tonemap::ToneMapping,
+    pub observer: Camera,
+    /// records and plays camera paths, while a path is played it moves the observer
+    pub flythrough: Flythrough,
+    pub scene: scene::Scene,
+    /// the instances that are turned on the GPU, they belong to a node of the scene
+    pub animation: Option<InstanceAnimation>,
+    /// what was clicked on, it is drawn highlighted
+    pub selected: Option<Selection>,
+    // the object that is drawn for the lights
+    light_object: scene::ObjectId,
+    pub instance_rot_speed: f32,
+    // only draw the instances that are inside the view frustum
+    pub frustum_culling: bool,
+    /// anisotropic filtering of the textures of the models that are loaded from now on,
+    /// None turns it off
+    pub texture_anisotropy: Option<u16>,
+    depth_texture: texture::Texture,
+    pub lights: light::Lights,
+    shadow_maps: shadow::ShadowMaps,
+    // let the lights circle around the y axis
+    pub orbit_lights: bool,
+}
+
+impl Renderer {
+    pub async fn new(
+        device: wgpu::Device,
+        queue: wgpu::Queue,
+        color_format: wgpu::TextureFormat,
+        width: u32,
+        height: u32,
+        supported_sample_counts: Vec<u32>,
+    ) -> Self {
+        let material_layouts = model::MaterialLayouts::new(&device);
+
+        // all the stuff that is needed to initialize the observer of the scene
+        let observer = Camera::new(
+            (0.0, 5.0, 10.0),
+            cgmath::Deg(-90.0),
+            cgmath::Deg(-20.0),
+            width,
+            height,
+            0.1,
+            100.0,
+            cgmath::Deg(45.0),
+            &device,
+            4.0, 0.4,
+            &queue
+        );
+
+        let mut lights = light::Lights::new(&device);
+        lights.update_gpu_state(&device, &queue);
+        let mut shadow_maps = shadow::ShadowMaps::new(&device);
+        shadow_maps.update(lights.iter(), &queue);
+
+        // use multisampling if the adapter can do it
+        let sample_count = if supported_sample_counts.contains(&4) { 4 } else { 1 };
+        let depth_texture = texture::Texture::create_depth_texture(&device, width, height, sample_count, "depth texture");
+        let msaa_view = create_msaa_view(&device, width, height, sample_count);
+        let mut tone_mapping = tonemap::ToneMapping::new(&device, color_format, width, height);
+        tone_mapping.update_gpu_state(&queue);
+
+        let render_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Render Pipeline Layout"),
+                bind_group_layouts: &[
+                    &material_layouts.phong,
+                    &observer.uniform.bind_group_layout,
+                    &lights.bind_group_layout,
+                    &shadow_maps.bind_group_layout,
+                ],
+                push_constant_ranges: &[],
+            });
+        // the same as the Phong pipeline except for the material
+        let pbr_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("PBR Render Pipeline Layout"),
+            bind_group_layouts: &[
+                &material_layouts.pbr,
+                &observer.uniform.bind_group_layout,
+                &lights.bind_group_layout,
+                &shadow_maps.bind_group_layout,
+            ],
+            push_constant_ranges: &[],
+        });
+        let light_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Light Render Pipeline"),
+            bind_group_layouts: &[&observer.uniform.bind_group_layout, &lights.bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let (render_pipeline, pbr_render_pipeline, light_render_pipeline) = create_scene_pipelines(
+            &device,
+            &render_pipeline_layout,
+            &pbr_pipeline_layout,
+            &light_pipeline_layout,
+            sample_count,
+            observer.projection.depth_mode(),
+        );
+        let highlight_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Highlight Pipeline Layout"),
+            bind_group_layouts: &[&observer.uniform.bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let highlight_pipeline = create_highlight_pipeline(&device, &highlight_pipeline_layout, sample_count, observer.projection.depth_mode());
+
+        // here we load the model and that we are going to render in this case it is a cube
+        let mut scene = scene::Scene::new(&device);
+        let cube = scene.add_model(
+            "cube",
+            "cube.obj",
+            resources::load_model("cube.obj", &device, &queue, &material_layouts, DEFAULT_TEXTURE_ANISOTROPY)
+                .await
+                .unwrap()
+        );
+        let cube = scene.model_object(cube).expect("an OBJ file is a single object");
+
+        // the grid of cubes hangs below one node, so it can be moved as a whole, the cubes
+        // themselves are placed and turned on the GPU
+        let grid = scene.add_node("grid", None, Instance::identity(), None);
+        scene.update_gpu_state(&device, &queue, Some(&Frustum::from_matrix(&observer.view.view_proj.into())));
+        let animation = InstanceAnimation::new(&device, &queue, &scene, grid, cube, Layout::default());
+
+        Self {
+            device,
+            queue,
+            render_pipeline_layout,
+            render_pipeline,
+            pbr_pipeline_layout,
+            pbr_render_pipeline,
+            light_pipeline_layout,
+            light_render_pipeline,
+            highlight_pipeline_layout,
+            highlight_pipeline,
+            material_layouts,
+            size: (width, height),
+            sample_count,
+            supported_sample_counts,
+            msaa_view,
+            tone_mapping,
+            observer,
+            flythrough: Flythrough::new(CAMERA_PATH_FILE),
+            scene,
+            animation: Some(animation),
+            selected: None,
+            light_object: cube,
+            instance_rot_speed: 1.,
+            frustum_culling: true,
+            texture_anisotropy: DEFAULT_TEXTURE_ANISOTROPY,
+            depth_texture,
+            lights,
+            shadow_maps,
+            orbit_lights: true,
+        }
+    }
+
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.size = (width, height);
+        self.observer.projection.resize(width, height);
+        self.depth_texture = texture::Texture::create_depth_texture(&self.device, width, height, self.sample_count, "depth texture");
+        self.msaa_view = create_msaa_view(&self.device, width, height, self.sample_count);
+        self.tone_mapping.resize(&self.device, width, height);
+    }
+
+    pub fn sample_count(&self) -> u32 {
+        self.sample_count
+    }
+
+    /// The MSAA sample counts that both the color and the depth format support on the adapter
+    pub fn supported_sample_counts(&self) -> &[u32] {
+        &self.supported_sample_counts
+    }
+
+    /// Change the number of samples per pixel. This rebuilds the pipelines and the render
+    /// targets, so it should not be done every frame.
+    pub fn set_sample_count(&mut self, sample_count: u32) -> anyhow::Result<()> {
+        if !self.supported_sample_counts.contains(&sample_count) {
+            anyhow::bail!("{}x MSAA is not supported by the adapter", sample_count);
+        }
+        if sample_count == self.sample_count {
+            return Ok(());
+        }
+        self.sample_count = sample_count;
+        self.rebuild_pipelines();
+        self.resize(self.size.0, self.size.1);
+        Ok(())
+    }
+
+    pub fn depth_mode(&self) -> DepthMode {
+        self.observer.projection.depth_mode()
+    }
+
+    /// Switch between standard and reverse-Z depth. The projection and the depth test of
+    /// the pipelines change together, so the pipelines are rebuilt.
+    pub fn set_depth_mode(&mut self, depth_mode: DepthMode) {
+        if depth_mode == self.depth_mode() {
+            return;
+        }
+        self.observer.projection.set_depth_mode(depth_mode);
+        self.observer.update_gpu_state(&self.queue);
+        self.rebuild_pipelines();
+    }
+
+    fn rebuild_pipelines(&mut self) {
+        (self.render_pipeline, self.pbr_render_pipeline, self.light_render_pipeline) = create_scene_pipelines(
+            &self.device,
+            &self.render_pipeline_layout,
+            &self.pbr_pipeline_layout,
+            &self.light_pipeline_layout,
+            self.sample_count,
+            self.depth_mode(),
+        );
+        self.highlight_pipeline = create_highlight_pipeline(&self.device, &self.highlight_pipeline_layout, self.sample_count, self.depth_mode());
+    }
+
+    /// Replace the scene, the lights and the camera with the ones from a scene file. The
+    /// animated instances are replaced as well, they only come from the node of the file
+    /// that has `instances`. The nodes of the models are created from their files again,
+    /// so changes to them are not kept in scene files.
+    pub async fn load_scene(&mut self, description: &SceneDescription) -> anyhow::Result<()> {
+        // the projection asserts on these, so they are checked before anything is changed
+        let camera = &description.camera;
+        anyhow::ensure!(
+            camera.field_of_view > 0.0 && camera.field_of_view < 180.0,
+            "the field of view of the camera has to be between 0 and 180 degrees, it is {}",
+            camera.field_of_view,
+        );
+        anyhow::ensure!(
+            camera.znear > 0.0 && camera.znear < camera.zfar,
+            "the camera needs 0 < znear < zfar, they are {} and {}",
+            camera.znear,
+            camera.zfar,
+        );
+        let mut scene = scene::Scene::new(&self.device);
+        for model in &description.models {
+            let file = resources::load_model(&model.file, &self.device, &self.queue, &self.material_layouts, self.texture_anisotropy).await?;
+            scene.add_model(&model.name, &model.file, file);
+        }
+        // the lights are drawn as cubes, so the cube is needed even if no node uses it
+        let cube = scene.models()
+            .find(|(_, _, file)| *file == "cube.obj")
+            .and_then(|(id, _, _)| scene.model_object(id));
+        let light_object = match cube {
+            Some(id) => id,
+            None => {
+                let cube = resources::load_model("cube.obj", &self.device, &self.queue, &self.material_layouts, self.texture_anisotropy).await?;
+                let cube = scene.add_model("light gizmo", "cube.obj", cube);
+                scene.model_object(cube).expect("an OBJ file is a single object")
+            }
+        };
+
+        let find_model = |scene: &scene::Scene, node: &NodeDescription, model: &str| {
+            scene.models()
+                .find(|(_, name, _)| *name == model)
+                .map(|(id, _, _)| id)
+                .ok_or_else(|| anyhow::anyhow!("node {:?} uses the unknown model {:?}", node.name, model))
+        };
+        // the node with the animated instances, their model and their layout
+        let mut animated = None;
+        let mut stack = description.nodes.iter().rev().map(|node| (node, None)).collect::<Vec<_>>();
+        while let Some((node, parent)) = stack.pop() {
+            let id = match &node.model {
+                Some(model) => {
+                    let model = find_model(&scene, node, model)?;
+                    scene.add_model_node(&node.name, parent, node.transform(), model)
+                }
+                None => scene.add_node(&node.name, parent, node.transform(), None),
+            };
+            if let Some(instances) = &node.instances {
+                anyhow::ensure!(animated.is_none(), "node {:?} has animated instances, but only one node can have them", node.name);
+                let mut layout = instances.layout.clone();
+                if let Layout::Csv { file, positions } = &mut layout {
+                    *positions = layout::load_csv(&*file)?;
+                }
+                layout.check()?;
+                let model = find_model(&scene, node, &instances.model)?;
+                let object = scene.model_object(model).ok_or_else(|| {
+                    anyhow::anyhow!("the instances of node {:?} need a model that is a single object, {:?} is not", node.name, instances.model)
+                })?;
+                animated = Some((id, object, layout));
+            }
+            // reversed so the children keep their order
+            stack.extend(node.children.iter().rev().map(|child| (child, Some(id))));
+        }
+        self.observer.set_view(camera.position, Deg(camera.yaw), Deg(camera.pitch));
+        self.observer.projection.set_perspective(Deg(camera.field_of_view), camera.znear, camera.zfar);
+        self.observer.update_gpu_state(&self.queue);
+
+        // nothing can fail from here on, so a broken scene file leaves the old scene in place
+        self.scene = scene;
+        self.light_object = light_object;
+        self.selected = None;
+        self.scene.update_gpu_state(&self.device, &self.queue, self.frustum().as_ref());
+        self.animation = animated.map(|(node, object, layout)| {
+            InstanceAnimation::new(&self.device, &self.queue, &self.scene, node, object, layout)
+        });
+
+        self.lights.clear();
+        for light in &description.lights {
+            self.lights.add(light.to_light());
+        }
+        self.lights.update_gpu_state(&self.device, &self.queue);
+        self.shadow_maps.update(self.lights.iter(), &self.queue);
+        Ok(())
+    }
+
+    /// The current scene, lights and camera in the form they are written to a scene file
+    pub fn describe_scene(&self) -> SceneDescription {
+        let models = self.scene.models()
+            .map(|(_, name, file)| ModelDescription { name: name.to_string(), file: file.to_string() })
+            .collect::<Vec<_>>();
+        let nodes = self.scene.roots().iter().map(|&root| self.describe_node(root, &models)).collect();
+
+        let projection = &self.observer.projection;
+        SceneDescription {
+            nodes,
+            models,
+            lights: self.lights.iter().map(LightDescription::from_light).collect(),
+            camera: CameraDescription {
+                position: self.observer.position.into(),
+                yaw: Deg::from(self.observer.yaw()).0,
+                pitch: Deg::from(self.observer.pitch()).0,
+                field_of_view: Deg::from(projection.field_of_view()).0,
+                znear: projection.znear(),
+                zfar: projection.zfar(),
+            },
+        }
+    }
+
+    fn describe_node(&self, id: scene::NodeId, models: &[ModelDescription]) -> NodeDescription {
+        let node = self.scene.node(id);
+        let model = node.model.map(|model| models[model].name.clone());
+        let mut description = NodeDescription::from_transform(&node.name, model, &node.transform);
+        if let Some(animation) = self.animation.as_ref().filter(|animation| animation.node() == id) {
+            description.instances = Some(InstancesDescription {
+                model: models[self.scene.object_model(animation.object())].name.clone(),
+                layout: animation.layout().clone(),
+            });
+        }
+        // the nodes of a model come from its file
+        description.children = node.children().iter()
+            .filter(|&&child| !self.scene.node(child).is_part_of_model())
+            .map(|&child| self.describe_node(child, models))
+            .collect();
+        description
+    }
+
+    /// Select the instance under a point on the screen (in pixels from the top left), or
+    /// nothing if there is no instance
+    pub fn select_at(&mut self, x: f32, y: f32) {
+        let Some(ray) = self.observer.cursor_ray(x, y, self.size.0, self.size.1) else { return };
+        let node = self.scene.pick(&ray).map(|(id, distance)| (Selection::Node(id), distance));
+        let animated = self.animation.as_ref()
+            .and_then(|animation| animation.pick(&self.scene, &ray))
+            .map(|(index, distance)| (Selection::Animated(index), distance));
+        self.selected = node.into_iter().chain(animated)
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(selection, _)| selection);
+    }
+
+    /// The node that the selection is edited through: the selected node itself, or the
+    /// node that a selected animated instance belongs to
+    pub fn selected_node(&self) -> Option<scene::NodeId> {
+        match self.selected? {
+            Selection::Node(id) => Some(id),
+            Selection::Animated(_) => self.animation.as_ref().map(|animation| animation.node()),
+        }
+    }
+
+    /// Move the camera so that the selected instance fills the view
+    pub fn frame_selection(&mut self) {
+        let sphere = match self.selected {
+            Some(Selection::Node(id)) => self.scene.world_bounds(id).map(|(_, sphere)| sphere),
+            Some(Selection::Animated(index)) => self.animation.as_ref().map(|animation| animation.world_bounds(&self.scene, index)),
+            None => None,
+        };
+        if let Some(sphere) = sphere {
+            self.observer.frame(&sphere);
+        }
+    }
+
+    /// The number of instances that are inside and outside the frustum, including the
+    /// animated ones
+    pub fn culling_stats(&self) -> (usize, usize) {
+        let (visible, culled) = self.scene.culling_stats();
+        let (animated_visible, animated_culled) = self.animation.as_ref().map_or((0, 0), |animation| animation.culling_stats());
+        (visible + animated_visible, culled + animated_culled)
+    }
+
+    /// What the camera sees, if frustum culling is on
+    fn frustum(&self) -> Option<Frustum> {
+        self.frustum_culling.then(|| Frustum::from_matrix(&self.observer.view.view_proj.into()))
+    }
+
+    pub fn update(&mut self, dt: instant::Duration) {
+        // a played camera path takes over from the camera controls
+        if self.flythrough.is_playing() {
+            self.flythrough.play(dt, &mut self.observer, &self.queue);
+            self.observer.update_without_input(dt, &self.queue);
+        } else {
+            self.observer.update(dt, &self.queue);
+            self.flythrough.record(dt, &self.observer);
+        }
+        // the animated instances go with their node when it is removed
+        if self.animation.as_ref().is_some_and(|animation| !self.scene.contains(animation.node())) {
+            self.animation = None;
+        }
+        let selection_exists = match self.selected {
+            Some(Selection::Node(id)) => self.scene.contains(id),
+            Some(Selection::Animated(index)) => self.animation.as_ref().is_some_and(|animation| index < animation.count()),
+            None => true,
+        };
+        if !selection_exists {
+            self.selected = None;
+        }
+
+        // write the world transforms of all the instances to the buffer
+        let frustum = self.frustum();
+        self.scene.update_gpu_state(&self.device, &self.queue, frustum.as_ref());
+        // the instances are turned when the animation pass runs
+        if let Some(animation) = &mut self.animation {
+            animation.update(dt, self.instance_rot_speed, &self.scene, frustum.as_ref(), &self.device, &self.queue);
+        }
+
+        if self.orbit_lights {
+            let rotation = cgmath::Quaternion::from_axis_angle((0.0, 1.0, 0.0).into(), cgmath::Deg(1.0));
+            for light in self.lights.iter_mut() {
+                let old_position: cgmath::Vector3<_> = light.position.into();
+                light.position = (rotation * old_position).into();
+                let old_direction: cgmath::Vector3<_> = light.direction.into();
+                light.direction = (rotation * old_direction).into();
+            }
+        }
+        self.lights.update_gpu_state(&self.device, &self.queue);
+        self.shadow_maps.update(self.lights.iter(), &self.queue);
+        self.tone_mapping.update_gpu_state(&self.queue);
+    }
+
+    /// Record the passes that draw the scene into the given color target. The target has
+    /// to have the color format the renderer was created with and the size of the last
+    /// call to `resize`.
+    pub fn render(&self, encoder: &mut wgpu::CommandEncoder, view: &wgpu::TextureView) {
+        // the animated instances have to be in place before anything is drawn
+        if let Some(animation) = &self.animation {
+            animation.compute(encoder);
+        }
+        // the shadow maps have to be ready before the scene is drawn
+        let shadow_batches = self.scene.batches()
+            .map(|(object, instances)| (object, self.scene.instance_buffer(), instances))
+            .chain(self.animation.as_ref().map(|animation| animation.batch(&self.scene)))
+            .collect::<Vec<_>>();
+        self.shadow_maps.render(encoder, &shadow_batches);
+
+        let hdr_view = self.tone_mapping.hdr_view();
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Render Pass"),
+            // with multisampling the scene is drawn into the multisampled target and
+            // then resolved into the HDR target
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: self.msaa_view.as_ref().unwrap_or(hdr_view),
+                resolve_target: self.msaa_view.as_ref().map(|_| hdr_view),
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color {
+                        r: 0.001,
+                        g: 0.001,
+                        b: 0.001,
+                        a: 1.0,
+                    }),
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &self.depth_texture.view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(self.depth_mode().clear_value()),
+                    store: true }),
+                    stencil_ops: None,
+            }),
+        });
+        render_pass.set_vertex_buffer(1, self.scene.instance_buffer().slice(..));
+
+        // every light gets its own gizmo, the instance index selects the light
+        render_pass.set_pipeline(&self.light_render_pipeline);
+        render_pass.draw_light_model_instanced(
+            self.scene.object(self.light_object),
+            0..self.lights.len() as u32,
+            &self.observer.uniform.bind_group,
+            &self.lights.bind_group
+        );
+
+        render_pass.set_bind_group(3, &self.shadow_maps.bind_group, &[]);
+        let batches = self.scene.visible_batches()
+            .map(|(object, instances)| (object, self.scene.instance_buffer(), instances))
+            .chain(self.animation.as_ref().and_then(|animation| animation.visible_batch(&self.scene)));
+        // the material of a mesh decides which pipeline it is drawn with
+        for (object, instance_buffer, instances) in batches {
+            render_pass.set_vertex_buffer(1, instance_buffer.slice(..));
+            for mesh in &object.meshes {
+                let material = &object.materials[mesh.material];
+                render_pass.set_pipeline(match material.shading {
+                    model::Shading::Phong(_) => &self.render_pipeline,
+                    model::Shading::Pbr(_) => &self.pbr_render_pipeline,
+                });
+                render_pass.draw_mesh_instanced(
+                    mesh,
+                    material,
+                    instances.clone(),
+                    &self.observer.uniform.bind_group,
+                    &self.lights.bind_group
+                );
+            }
+        }
+
+        // the selection is drawn over itself in a translucent color
+        let selection = match self.selected {
+            Some(Selection::Node(id)) => self.scene.node(id).object
+                .zip(self.scene.instance_index(id))
+                .map(|(object, instance)| (object, self.scene.instance_buffer(), instance)),
+            Some(Selection::Animated(index)) => self.animation.as_ref()
+                .map(|animation| (animation.object(), animation.instance_buffer(), index)),
+            None => None,
+        };
+        if let Some((object, instance_buffer, instance)) = selection {
+            render_pass.set_pipeline(&self.highlight_pipeline);
+            render_pass.set_bind_group(0, &self.observer.uniform.bind_group, &[]);
+            render_pass.set_vertex_buffer(1, instance_buffer.slice(..));
+            for mesh in &self.scene.object(object).meshes {
+                render_pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+                render_pass.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+                render_pass.draw_indexed(0..mesh.num_elements, 0, instance..instance + 1);
+            }
+        }
+        drop(render_pass);
+
+        self.tone_mapping.render(encoder, view);
+    }
+}
+
+fn create_scene_pipelines(
+    device: &wgpu::Device,
+    render_pipeline_layout: &wgpu::PipelineLayout,
+    pbr_pipeline_layout: &wgpu::PipelineLayout,
+    light_pipeline_layout: &wgpu::PipelineLayout,
+    sample_count: u32,
+    depth_mode: DepthMode,
+) -> (wgpu::RenderPipeline, wgpu::RenderPipeline, wgpu::RenderPipeline) {
+    let depth = Some((texture::Texture::DEPTH_FORMAT, depth_mode.compare()));
+    // both scene shaders share the vertex shader and the lighting code in common.wgsl
+    let render_pipeline = {
+        let shader = wgpu::ShaderModuleDescriptor {
+            label: Some("Normal Shader"),
+            source: wgpu::ShaderSource::Wgsl(concat!(include_str!("common.wgsl"), include_str!("shader.wgsl")).into()),
+        };
+        create_render_pipeline(
+            device,
+            render_pipeline_layout,
+            tonemap::ToneMapping::HDR_FORMAT,
+            depth,
+            &[model::ModelVertex::desc(), GPUInstance::desc()],
+            shader,
+            sample_count,
+        )
+    };
+
+    let pbr_render_pipeline = {
+        let shader = wgpu::ShaderModuleDescriptor {
+            label: Some("PBR Shader"),
+            source: wgpu::ShaderSource::Wgsl(concat!(include_str!("common.wgsl"), include_str!("pbr.wgsl")).into()),
+        };
+        create_render_pipeline(
+            device,
+            pbr_pipeline_layout,
+            tonemap::ToneMapping::HDR_FORMAT,
+            depth,
+            &[model::ModelVertex::desc(), GPUInstance::desc()],
+            shader,
+            sample_count,
+        )
+    };
+
+    let light_render_pipeline = {
+        let shader = wgpu::ShaderModuleDescriptor {
+            label: Some("Light Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("light.wgsl").into()),
+        };
+        create_render_pipeline(
+            device,
+            light_pipeline_layout,
+            tonemap::ToneMapping::HDR_FORMAT,
+            depth,
+            &[model::ModelVertex::desc()],
+            shader,
+            sample_count)
+    };
+    (render_pipeline, pbr_render_pipeline, light_render_pipeline)
+}
+
+/// Like the scene pipelines, but it blends over what is already there and only draws where
+/// the depth is the same as in the depth buffer, so it can draw over an instance again
+fn create_highlight_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    sample_count: u32,
+    depth_mode: DepthMode,
+) -> wgpu::RenderPipeline {
+    let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some("Highlight Shader"),
+        source: wgpu::ShaderSource::Wgsl(include_str!("highlight.wgsl").into()),
+    });
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("Highlight Pipeline"),
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            module: &shader,
+            entry_point: "vs_main",
+            buffers: &[model::ModelVertex::desc(), GPUInstance::desc()],
+        },
+        primitive: wgpu::PrimitiveState {
+            cull_mode: Some(wgpu::Face::Back),
+            ..Default::default()
+        },
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: texture::Texture::DEPTH_FORMAT,
+            depth_write_enabled: false,
+            depth_compare: depth_mode.compare_or_equal(),
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        multisample: wgpu::MultisampleState {
+            count: sample_count,
+            ..Default::default()
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: &shader,
+            entry_point: "fs_main",
+            targets: &[Some(wgpu::ColorTargetState {
+                format: tonemap::ToneMapping::HDR_FORMAT,
+                blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        multiview: None,
+    })
+}
+
+/// The multisampled color target, there is none without multisampling
+fn create_msaa_view(
+    device: &wgpu::Device,
+    width: u32,
+    height: u32,
+    sample_count: u32,
+) -> Option<wgpu::TextureView> {
+    if sample_count == 1 {
+        return None;
+    }
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("multisampled color target"),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count,
+        dimension: wgpu::TextureDimension::D2,
+        format: tonemap::ToneMapping::HDR_FORMAT,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+        view_formats: &[],
+    });
+    Some(texture.create_view(&wgpu::TextureViewDescriptor::default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::Vector3;
+    use super::Selection;
+    use crate::headless::HeadlessRenderer;
+    use crate::layout::Layout;
+    use crate::scene_file::SceneDescription;
+
+    #[test]
+    fn default_scene_survives_saving() {
+        let Ok(mut headless) = pollster::block_on(HeadlessRenderer::new(64, 64, false)) else {
+            eprintln!("no adapter, skipping");
+            return;
+        };
+        let renderer = &mut headless.renderer;
+        let description = renderer.describe_scene();
+        let grid = &description.nodes[0];
+        assert_eq!(grid.name, "grid");
+        assert_eq!(grid.instances.as_ref().unwrap().layout, Layout::default());
+        pollster::block_on(renderer.load_scene(&description)).unwrap();
+        assert_eq!(renderer.animation.as_ref().unwrap().count(), 100);
+        assert_eq!(renderer.describe_scene().nodes, description.nodes);
+    }
+
+    #[test]
+    fn animated_instances_are_picked_and_culled() {
+        let Ok(mut headless) = pollster::block_on(HeadlessRenderer::new(64, 64, false)) else {
+            eprintln!("no adapter, skipping");
+            return;
+        };
+        let renderer = &mut headless.renderer;
+        renderer.update(instant::Duration::ZERO);
+        assert_eq!(renderer.culling_stats(), (100, 0));
+        renderer.select_at(32.0, 32.0);
+        assert!(matches!(renderer.selected, Some(Selection::Animated(_))), "{:?}", renderer.selected);
+        let grid = renderer.animation.as_ref().unwrap().node();
+        assert_eq!(renderer.selected_node(), Some(grid));
+
+        // moving the node behind the camera takes all of its instances out of the view
+        renderer.scene.node_mut(grid).transform.position.z = 50.0;
+        renderer.update(instant::Duration::ZERO);
+        assert_eq!(renderer.culling_stats(), (0, 100));
+        renderer.select_at(32.0, 32.0);
+        assert_eq!(renderer.selected, None);
+        headless.render().unwrap();
+    }
+
+    #[test]
+    fn broken_scene_leaves_the_old_one_in_place() {
+        let Ok(mut headless) = pollster::block_on(HeadlessRenderer::new(64, 64, false)) else {
+            eprintln!("no adapter, skipping");
+            return;
+        };
+        let renderer = &mut headless.renderer;
+        let before = renderer.describe_scene();
+        let light_object = renderer.light_object;
+        let broken = SceneDescription::from_ron(r#"(
+            models: [(name: "cube", file: "cube.obj"), (name: "other cube", file: "cube.obj")],
+            nodes: [
+                (name: "fine", model: Some("cube")),
+                (name: "broken", model: Some("missing")),
+            ],
+            lights: [(kind: Point, position: (2.0, 2.0, 2.0), intensity: 3.0)],
+        )"#).unwrap();
+        assert!(pollster::block_on(renderer.load_scene(&broken)).is_err());
+        assert_eq!(renderer.describe_scene(), before);
+        assert_eq!(renderer.light_object, light_object);
+
+        // the projection would assert on these
+        for camera in ["field_of_view: 0.0", "field_of_view: 180.0", "znear: 0.0", "znear: 10.0, zfar: 10.0"] {
+            let broken = SceneDescription::from_ron(&format!("(camera: ({}))", camera)).unwrap();
+            assert!(pollster::block_on(renderer.load_scene(&broken)).is_err(), "{}", camera);
+        }
+        assert_eq!(renderer.describe_scene(), before);
+        // the light gizmos are still drawn with an object of the old scene
+        headless.render().unwrap();
+    }
+
+    #[test]
+    fn oversized_layout_is_an_error() {
+        let Ok(mut headless) = pollster::block_on(HeadlessRenderer::new(64, 64, false)) else {
+            eprintln!("no adapter, skipping");
+            return;
+        };
+        let description = SceneDescription::from_ron(r#"(
+            models: [(name: "cube", file: "cube.obj")],
+            nodes: [(name: "grid", instances: Some((model: "cube", layout: Grid(size: (100000, 1, 100000), spacing: 1.0))))],
+        )"#).unwrap();
+        assert!(pollster::block_on(headless.renderer.load_scene(&description)).is_err());
+    }
+
+    #[test]
+    fn gltf_nodes_become_scene_nodes() {
+        let Ok(mut headless) = pollster::block_on(HeadlessRenderer::new(64, 64, false)) else {
+            eprintln!("no adapter, skipping");
+            return;
+        };
+        let renderer = &mut headless.renderer;
+        let description = SceneDescription::from_ron(r#"(
+            models: [(name: "nodes", file: "nodes.gltf")],
+            nodes: [(name: "placed", model: Some("nodes"), position: (0.0, 0.0, -5.0))],
+        )"#).unwrap();
+        pollster::block_on(renderer.load_scene(&description)).unwrap();
+        renderer.update(instant::Duration::ZERO);
+
+        let scene = &renderer.scene;
+        let placed = scene.roots()[0];
+        let [parent, mirrored] = scene.node(placed).children() else { panic!("the file has two root nodes") };
+        let [child] = scene.node(*parent).children() else { panic!("the parent node has one child") };
+        assert_eq!(scene.nodes().count(), 4);
+        assert_eq!(scene.node(*child).name, "child");
+        assert_eq!(scene.node(*child).world().w.truncate(), Vector3::new(1.0, 2.0, -5.0));
+        assert_eq!(scene.node(*child).world().x.x, 2.0);
+        // the nodes share the object of the mesh, except for the mirrored one
+        let object = scene.node(*parent).object.unwrap();
+        assert_eq!(scene.node(*child).object, Some(object));
+        let mirrored_object = scene.node(*mirrored).object.unwrap();
+        assert_ne!(mirrored_object, object);
+        assert_eq!(scene.object(object).meshes[0].indices, [0, 1, 2]);
+        assert_eq!(scene.object(mirrored_object).meshes[0].indices, [0, 2, 1]);
+
+        // the nodes of the file are not written to the scene file
+        assert_eq!(renderer.describe_scene().nodes, description.nodes);
+        headless.render().unwrap();
+    }
+}
@@ -1,10 +1,18 @@
+use std::collections::HashMap;
 use std::io::{BufReader, Cursor};
+use std::rc::Rc;
 
+use anyhow::Context;
 use cfg_if::cfg_if;
 use wgpu::util::DeviceExt;
 
+use crate::bounds::{Aabb, BoundingSphere};
 use crate::{model, texture};
 
+// The specular exponent of materials without one, a missing Ns is read as 0, which would
+// turn the whole surface into a highlight
+const DEFAULT_SHININESS: f32 = 32.0;
+
 #[cfg(target_arch = "wasm32")]
 fn format_url(file_name: &str) -> reqwest::Url {
     let window = web_sys::window().unwrap();
@@ -59,18 +67,58 @@ pub async fn load_binary(file_name: &str) -> anyhow::Result<Vec<u8>> {
 
 pub async fn load_texture (
     file_name: &str,
+    is_normal_map: bool,
     device: &wgpu::Device,
     queue: &wgpu::Queue,
+    anisotropy: Option<u16>,
 ) -> anyhow::Result<texture::Texture> {
     let data = load_binary(file_name).await?;
-    texture::Texture::from_bytes(device, queue, &data, file_name)
+    texture::Texture::from_bytes(device, queue, &data, file_name, is_normal_map, anisotropy)
+}
+
+/// Load a texture of a material, if the material does not have one (`file_name` is empty)
+/// a 1x1 texture of the given color is used instead
+async fn load_optional_texture(
+    file_name: &str,
+    is_normal_map: bool,
+    color: [u8; 4],
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    anisotropy: Option<u16>,
+) -> anyhow::Result<texture::Texture> {
+    if file_name.is_empty() {
+        texture::Texture::from_color(device, queue, color, "default texture", is_normal_map)
+    } else {
+        load_texture(file_name, is_normal_map, device, queue, anisotropy).await
+    }
 }
 
+/// Load a model from an OBJ file or a glTF file (`.gltf` or `.glb`), depending on the
+/// extension of `file_name`. The textures are filtered anisotropically with up to
+/// `anisotropy` samples, None turns it off.
 pub async fn load_model(
     file_name: &str,
     device: &wgpu::Device,
     queue: &wgpu::Queue,
-    layout: &wgpu::BindGroupLayout,
+    layouts: &model::MaterialLayouts,
+    anisotropy: Option<u16>,
+) -> anyhow::Result<model::ModelFile> {
+    let extension = std::path::Path::new(file_name)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("gltf") | Some("glb") => load_gltf(file_name, device, queue, layouts, anisotropy).await,
+        _ => Ok(model::ModelFile::single(file_name, load_obj(file_name, device, queue, layouts, anisotropy).await?)),
+    }
+}
+
+async fn load_obj(
+    file_name: &str,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layouts: &model::MaterialLayouts,
+    anisotropy: Option<u16>,
 ) -> anyhow::Result<model::Object> {
     let obj_text = load_string(file_name).await?;
     let obj_cursor = Cursor::new(obj_text);
@@ -92,32 +140,63 @@ pub async fn load_model(
     let mut materials = Vec::new();
 
     for m in obj_materials? {
-        // get the texture for that material
-        let diffuse_texture = load_texture(&m.diffuse_texture, device, queue).await?;
-        // create the bind group for a given texture
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: None,
-            layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&diffuse_texture.view)
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler)
-                },
-            ],
-        });
-        materials.push(model::Material {
-            name: m.name,
-            diffuse_texture,
-            bind_group,
-        })
+        // the normal map is referenced via map_Bump in the mtl file
+        let normal_texture = if m.normal_texture.is_empty() {
+            texture::Texture::flat_normal_map(device, queue)?
+        } else {
+            load_texture(&m.normal_texture, true, device, queue, anisotropy).await?
+        };
+        // tobj does not know about the emissive color and the PBR extension of the format,
+        // so they end up with the unknown parameters
+        let emissive = m.unknown_param.get("Ke")
+            .map(|ke| parse_color(ke).with_context(|| format!("invalid Ke in material {}", m.name)))
+            .transpose()?
+            .unwrap_or([0.0; 3]);
+        let pbr_param = |key: &str| m.unknown_param.get(key).map(|value| value.trim());
+        let is_pbr = ["Pr", "Pm", "map_Pr", "map_Pm"].iter().any(|key| pbr_param(key).is_some());
+
+        let material = if is_pbr {
+            let parse_factor = |key: &str, default: f32| {
+                pbr_param(key)
+                    .map(|value| value.parse::<f32>().with_context(|| format!("invalid {} in material {}", key, m.name)))
+                    .transpose()
+                    .map(|factor| factor.unwrap_or(default))
+            };
+            // the textures with data instead of colors are loaded like normal maps, without Srgb
+            let textures = model::PbrTextures {
+                base_color: load_optional_texture(&m.diffuse_texture, false, [255; 4], device, queue, anisotropy).await?,
+                normal: normal_texture,
+                metallic: load_optional_texture(pbr_param("map_Pm").unwrap_or(""), true, [255; 4], device, queue, anisotropy).await?,
+                roughness: load_optional_texture(pbr_param("map_Pr").unwrap_or(""), true, [255; 4], device, queue, anisotropy).await?,
+                // there is no occlusion map in the mtl format, but some exporters write map_ao
+                occlusion: load_optional_texture(pbr_param("map_ao").unwrap_or(""), true, [255; 4], device, queue, anisotropy).await?,
+                emissive: load_optional_texture(pbr_param("map_Ke").unwrap_or(""), false, [255; 4], device, queue, anisotropy).await?,
+            };
+            let uniform = model::PbrMaterialUniform::new(
+                [m.diffuse[0], m.diffuse[1], m.diffuse[2], m.dissolve],
+                parse_factor("Pm", 0.0)?,
+                parse_factor("Pr", 1.0)?,
+                1.0,
+                emissive,
+            );
+            model::Material::pbr(device, layouts, m.name, textures, uniform)
+        } else {
+            let diffuse_texture = load_optional_texture(&m.diffuse_texture, false, [255; 4], device, queue, anisotropy).await?;
+            let uniform = model::MaterialUniform::new(
+                m.ambient,
+                m.diffuse,
+                m.specular,
+                if m.shininess > 0.0 { m.shininess } else { DEFAULT_SHININESS },
+                m.dissolve,
+                emissive,
+            );
+            model::Material::phong(device, layouts, m.name, diffuse_texture, normal_texture, uniform)
+        };
+        materials.push(material);
     }
 
     let meshes = models.into_iter().map(|m| {
-        let vertices = (0..m.mesh.positions.len() / 3).map(|i| model::ModelVertex{
+        let mut vertices = (0..m.mesh.positions.len() / 3).map(|i| model::ModelVertex{
             position: [
                 m.mesh.positions[i*3],
                 m.mesh.positions[i*3+1],
@@ -129,27 +208,349 @@ pub async fn load_model(
                 m.mesh.normals[i * 3 + 1],
                 m.mesh.normals[i * 3 + 2],
             ],
+            // these get calculated from the triangles below
+            tangent: [0.0; 3],
+            bitangent: [0.0; 3],
         }).collect::<Vec<_>>();
+        compute_tangents(&mut vertices, &m.mesh.indices);
+        create_mesh(device, file_name, &vertices, &m.mesh.indices, m.mesh.material_id.unwrap_or(0))
+    }).collect::<Vec<_>>();
+    Ok(model::Object::new(meshes, materials.into_iter().map(Rc::new).collect()))
+}
+
+/// Upload the vertices and indices of a mesh to the GPU
+fn create_mesh(
+    device: &wgpu::Device,
+    name: &str,
+    vertices: &[model::ModelVertex],
+    indices: &[u32],
+    material: usize,
+) -> model::Mesh {
+    let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor{
+        label: Some(&format!("{:?} Vertex Buffer", name)),
+        contents: bytemuck::cast_slice(vertices),
+        usage: wgpu::BufferUsages::VERTEX,
+    });
+    let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some(&format!("{:?} Index Buffer", name)),
+        contents: bytemuck::cast_slice(indices),
+        usage: wgpu::BufferUsages::INDEX,
+    });
+    let positions = vertices.iter().map(|v| cgmath::Point3::from(v.position)).collect::<Vec<_>>();
+    model::Mesh {
+        vertex_buffer,
+        index_buffer,
+        num_elements: indices.len() as u32,
+        material,
+        aabb: Aabb::from_points(positions.iter().copied()),
+        bounding_sphere: BoundingSphere::from_points(positions.iter().copied()),
+        positions,
+        indices: indices.to_vec(),
+    }
+}
+
+/// Load a glTF 2.0 model, either a `.gltf` file with the buffers and images next to it (or
+/// embedded as data URIs) or a binary `.glb` file with everything in it. All the materials
+/// use the metallic-roughness model and are drawn with the PBR pipeline.
+///
+/// The nodes of the default scene are kept with their transforms, every mesh becomes one
+/// object that all the nodes using it share. As back faces are culled, a mesh under a
+/// mirroring transform gets a second object with the triangles turned the other way round.
+async fn load_gltf(
+    file_name: &str,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layouts: &model::MaterialLayouts,
+    anisotropy: Option<u16>,
+) -> anyhow::Result<model::ModelFile> {
+    use cgmath::{Matrix4, SquareMatrix};
+
+    let data = load_binary(file_name).await?;
+    let gltf = gltf::Gltf::from_slice(&data).with_context(|| format!("failed to parse {}", file_name))?;
+
+    let mut buffers = Vec::new();
+    for buffer in gltf.buffers() {
+        let data = match buffer.source() {
+            gltf::buffer::Source::Bin => gltf.blob.clone()
+                .ok_or_else(|| anyhow::anyhow!("{} has no binary chunk", file_name))?,
+            gltf::buffer::Source::Uri(uri) => load_gltf_uri(file_name, uri).await?,
+        };
+        anyhow::ensure!(data.len() >= buffer.length(), "buffer {} of {} is too short", buffer.index(), file_name);
+        buffers.push(data);
+    }
 
-        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor{
-            label: Some(&format!("{:?} Vertex Buffer", file_name)),
-            contents: bytemuck::cast_slice(&vertices),
-            usage: wgpu::BufferUsages::VERTEX,
-        });
-        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some(&format!("{:?} Index Buffer", file_name)),
-            contents: bytemuck::cast_slice(&m.mesh.indices),
-            usage: wgpu::BufferUsages::INDEX,
-        });
-        model::Mesh {
-            name: file_name.to_string(),
-            vertex_buffer,
-            index_buffer,
-            num_elements: m.mesh.indices.len() as u32,
-            material: m.mesh.material_id.unwrap_or(0),
-            
+    // the images are decoded up front because a packed metallic-roughness image is
+    // turned into two textures
+    let mut images = Vec::new();
+    for image in gltf.images() {
+        let bytes = match image.source() {
+            gltf::image::Source::View { view, .. } => {
+                buffers[view.buffer().index()].get(view.offset()..view.offset() + view.length())
+                    .ok_or_else(|| anyhow::anyhow!("image {} of {} lies outside of its buffer", image.index(), file_name))?
+                    .to_vec()
+            }
+            gltf::image::Source::Uri { uri, .. } => load_gltf_uri(file_name, uri).await?,
+        };
+        images.push(image::load_from_memory(&bytes).with_context(|| format!("failed to decode image {} of {}", image.index(), file_name))?);
+    }
+    let load_texture = |texture: Option<gltf::Texture>, is_normal_map: bool, color: [u8; 4]| match texture {
+        Some(texture) => {
+            let label = texture.name().unwrap_or(file_name);
+            texture::Texture::from_image(device, queue, &images[texture.source().index()], Some(label), is_normal_map, anisotropy)
         }
-    }).collect::<Vec<_>>();
-    Ok(model::Object { meshes, materials })
+        None => texture::Texture::from_color(device, queue, color, "default texture", is_normal_map),
+    };
+
+    // primitives without a material get the default material of the spec, which is what
+    // the material of such a primitive returns
+    let default_material = gltf.meshes()
+        .flat_map(|mesh| mesh.primitives())
+        .map(|primitive| primitive.material())
+        .find(|material| material.index().is_none());
+    let mut materials = Vec::new();
+    for material in gltf.materials().chain(default_material) {
+        let pbr = material.pbr_metallic_roughness();
+        let metallic_roughness = pbr.metallic_roughness_texture().map(|info| info.texture());
+        let occlusion = material.occlusion_texture();
+        let occlusion_strength = occlusion.as_ref().map(|o| o.strength()).unwrap_or(1.0);
+        // the textures with data instead of colors are loaded like normal maps, without Srgb
+        let textures = model::PbrTextures {
+            base_color: load_texture(pbr.base_color_texture().map(|info| info.texture()), false, [255; 4])?,
+            normal: load_texture(material.normal_texture().map(|n| n.texture()), true, [128, 128, 255, 255])?,
+            metallic: load_texture(metallic_roughness.clone(), true, [255; 4])?,
+            roughness: load_texture(metallic_roughness, true, [255; 4])?,
+            occlusion: load_texture(occlusion.map(|o| o.texture()), true, [255; 4])?,
+            emissive: load_texture(material.emissive_texture().map(|info| info.texture()), false, [255; 4])?,
+        };
+        let uniform = model::PbrMaterialUniform::new(
+            pbr.base_color_factor(),
+            pbr.metallic_factor(),
+            pbr.roughness_factor(),
+            occlusion_strength,
+            material.emissive_factor(),
+        );
+        let name = material.name().unwrap_or("default material").to_string();
+        materials.push(Rc::new(model::Material::pbr(device, layouts, name, textures, uniform)));
+    }
+    let default_material = gltf.materials().len();
+
+    let scene = gltf.default_scene()
+        .or_else(|| gltf.scenes().next())
+        .ok_or_else(|| anyhow::anyhow!("{} does not contain a scene", file_name))?;
+    let mut loader = GltfMeshes {
+        file_name,
+        device,
+        buffers: &buffers,
+        materials: &materials,
+        default_material,
+        objects: Vec::new(),
+        mesh_objects: HashMap::new(),
+    };
+    let nodes = scene.nodes()
+        .map(|node| loader.node(node, Matrix4::identity()))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(model::ModelFile { objects: loader.objects, nodes })
+}
+
+// Turns the nodes of a glTF file into model nodes and loads the meshes they use
+struct GltfMeshes<'a> {
+    file_name: &'a str,
+    device: &'a wgpu::Device,
+    buffers: &'a [Vec<u8>],
+    materials: &'a [Rc<model::Material>],
+    default_material: usize,
+    objects: Vec<model::Object>,
+    // the object of every mesh that was loaded, as it is or mirrored
+    mesh_objects: HashMap<(usize, bool), usize>,
+}
+
+impl GltfMeshes<'_> {
+    fn node(&mut self, node: gltf::Node, parent_transform: cgmath::Matrix4<f32>) -> anyhow::Result<model::ModelNode> {
+        use cgmath::SquareMatrix;
+        let (translation, [x, y, z, w], scale) = node.transform().decomposed();
+        let transform = model::Instance {
+            position: translation.into(),
+            rotation: cgmath::Quaternion::new(w, x, y, z),
+            scale: scale.into(),
+        };
+        // only the transforms within the file are known here, a node of the scene that
+        // mirrors the whole model is not taken into account
+        let world = parent_transform * transform.to_matrix();
+        let object = match node.mesh() {
+            Some(mesh) => Some(self.mesh(mesh, world.determinant() < 0.0)?),
+            None => None,
+        };
+        Ok(model::ModelNode {
+            name: node.name().map(str::to_string).unwrap_or_else(|| format!("node {}", node.index())),
+            transform,
+            object,
+            children: node.children().map(|child| self.node(child, world)).collect::<anyhow::Result<_>>()?,
+        })
+    }
+
+    // the index of the object of a mesh, it is only loaded the first time
+    fn mesh(&mut self, mesh: gltf::Mesh, mirrored: bool) -> anyhow::Result<usize> {
+        if let Some(&object) = self.mesh_objects.get(&(mesh.index(), mirrored)) {
+            return Ok(object);
+        }
+        let name = mesh.name().unwrap_or(self.file_name);
+        let mut meshes = Vec::new();
+        for primitive in mesh.primitives() {
+            if primitive.mode() != gltf::mesh::Mode::Triangles {
+                log::warn!("skipping a primitive of {} in {} that is not made of triangles", name, self.file_name);
+                continue;
+            }
+            let reader = primitive.reader(|buffer| Some(&self.buffers[buffer.index()][..]));
+            let positions = reader.read_positions()
+                .ok_or_else(|| anyhow::anyhow!("a primitive of {} in {} has no positions", name, self.file_name))?
+                .collect::<Vec<_>>();
+            let mut indices = match reader.read_indices() {
+                Some(indices) => indices.into_u32().collect::<Vec<_>>(),
+                None => (0..positions.len() as u32).collect(),
+            };
+            anyhow::ensure!(
+                indices.iter().all(|&i| (i as usize) < positions.len()),
+                "a primitive of {} in {} has indices past its {} vertices", name, self.file_name, positions.len(),
+            );
+            let normals = match reader.read_normals() {
+                Some(normals) => normals.collect::<Vec<_>>(),
+                None => compute_normals(&positions, &indices),
+            };
+            let tex_coords = reader.read_tex_coords(0)
+                .map(|t| t.into_f32().collect::<Vec<_>>())
+                .unwrap_or_else(|| vec![[0.0; 2]; positions.len()]);
+            anyhow::ensure!(
+                normals.len() == positions.len() && tex_coords.len() == positions.len(),
+                "the attributes of a primitive of {} in {} have different counts", name, self.file_name,
+            );
+            // a mirroring transform turns the triangles inside out, turning them around
+            // again keeps them from being culled
+            if mirrored {
+                for triangle in indices.chunks_exact_mut(3) {
+                    triangle.swap(1, 2);
+                }
+            }
+
+            let mut vertices = positions.into_iter().zip(normals).zip(tex_coords).map(|((position, normal), tex_coords)| {
+                model::ModelVertex {
+                    position,
+                    tex_coords,
+                    normal,
+                    // these get calculated from the triangles below
+                    tangent: [0.0; 3],
+                    bitangent: [0.0; 3],
+                }
+            }).collect::<Vec<_>>();
+            compute_tangents(&mut vertices, &indices);
+            let material = primitive.material().index().unwrap_or(self.default_material);
+            meshes.push(create_mesh(self.device, name, &vertices, &indices, material));
+        }
+        self.objects.push(model::Object::new(meshes, self.materials.to_vec()));
+        let object = self.objects.len() - 1;
+        self.mesh_objects.insert((mesh.index(), mirrored), object);
+        Ok(object)
+    }
+}
+
+/// Resolve a URI of a glTF file, either a base64 data URI or a path relative to the file
+async fn load_gltf_uri(file_name: &str, uri: &str) -> anyhow::Result<Vec<u8>> {
+    use base64::Engine;
+    if let Some(data) = uri.strip_prefix("data:") {
+        let (_, encoded) = data.split_once(";base64,")
+            .ok_or_else(|| anyhow::anyhow!("only base64 data URIs are supported in {}", file_name))?;
+        return Ok(base64::engine::general_purpose::STANDARD.decode(encoded)?);
+    }
+    let path = match file_name.rfind('/') {
+        Some(i) => format!("{}/{}", &file_name[..i], uri),
+        None => uri.to_string(),
+    };
+    load_binary(&path).await.with_context(|| format!("failed to load {} referenced by {}", path, file_name))
+}
+
+/// Smooth normals for meshes that come without any, every vertex gets the average of the
+/// normals of the triangles it is part of (weighted by their area)
+fn compute_normals(positions: &[[f32; 3]], indices: &[u32]) -> Vec<[f32; 3]> {
+    use cgmath::{InnerSpace, Vector3, Zero};
+    let mut normals = vec![Vector3::zero(); positions.len()];
+    for c in indices.chunks_exact(3) {
+        let p0: Vector3<f32> = positions[c[0] as usize].into();
+        let p1: Vector3<f32> = positions[c[1] as usize].into();
+        let p2: Vector3<f32> = positions[c[2] as usize].into();
+        let normal = (p1 - p0).cross(p2 - p0);
+        for &i in c {
+            normals[i as usize] += normal;
+        }
+    }
+    normals.into_iter().map(|n| {
+        if n.magnitude2() > 0.0 { n.normalize().into() } else { [0.0, 1.0, 0.0] }
+    }).collect()
+}
+
+/// Parse a color given as three floats like in `Ke 1.0 0.5 0.0`
+fn parse_color(value: &str) -> anyhow::Result<[f32; 3]> {
+    let components = value
+        .split_whitespace()
+        .map(|c| c.parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()?;
+    match components[..] {
+        [r, g, b] => Ok([r, g, b]),
+        _ => anyhow::bail!("expected 3 components but got {}", components.len()),
+    }
 }
 
+/// Calculate the tangent and bitangent of every vertex from the triangles it is part of.
+/// The tangent space of a triangle is the one in which the texture coordinates change
+/// along the tangent (u) and bitangent (v) directions, as this is the space the normal
+/// map is expressed in. Vertices that are shared between triangles get the average.
+fn compute_tangents(vertices: &mut [model::ModelVertex], indices: &[u32]) {
+    use cgmath::{InnerSpace, Vector2, Vector3};
+    let mut triangles_included = vec![0u32; vertices.len()];
+
+    for c in indices.chunks_exact(3) {
+        let v0 = vertices[c[0] as usize];
+        let v1 = vertices[c[1] as usize];
+        let v2 = vertices[c[2] as usize];
+
+        let pos0: Vector3<f32> = v0.position.into();
+        let pos1: Vector3<f32> = v1.position.into();
+        let pos2: Vector3<f32> = v2.position.into();
+
+        let uv0: Vector2<f32> = v0.tex_coords.into();
+        let uv1: Vector2<f32> = v1.tex_coords.into();
+        let uv2: Vector2<f32> = v2.tex_coords.into();
+
+        // the edges of the triangle in model space and in texture space
+        let delta_pos1 = pos1 - pos0;
+        let delta_pos2 = pos2 - pos0;
+        let delta_uv1 = uv1 - uv0;
+        let delta_uv2 = uv2 - uv0;
+
+        // Solving
+        //     delta_pos1 = delta_uv1.x * T + delta_uv1.y * B
+        //     delta_pos2 = delta_uv2.x * T + delta_uv2.y * B
+        // for T and B. Triangles with degenerate texture coordinates are skipped.
+        let det = delta_uv1.x * delta_uv2.y - delta_uv1.y * delta_uv2.x;
+        if det.abs() < f32::EPSILON {
+            continue;
+        }
+        let r = 1.0 / det;
+        let tangent = (delta_pos1 * delta_uv2.y - delta_pos2 * delta_uv1.y) * r;
+        // the texture coordinates are flipped in wgpu, so the bitangent has to be too
+        let bitangent = (delta_pos2 * delta_uv1.x - delta_pos1 * delta_uv2.x) * -r;
+
+        for &i in c {
+            let i = i as usize;
+            vertices[i].tangent = (tangent + Vector3::from(vertices[i].tangent)).into();
+            vertices[i].bitangent = (bitangent + Vector3::from(vertices[i].bitangent)).into();
+            triangles_included[i] += 1;
+        }
+    }
+
+    for (v, n) in vertices.iter_mut().zip(triangles_included) {
+        if n == 0 {
+            continue;
+        }
+        v.tangent = Vector3::from(v.tangent).normalize().into();
+        v.bitangent = Vector3::from(v.bitangent).normalize().into();
+    }
+}
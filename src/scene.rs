@@ -0,0 +1,390 @@
+use std::mem;
+use std::ops::Range;
+use cgmath::{Matrix4, SquareMatrix};
+use crate::bounds::{Aabb, BoundingSphere, Frustum, Ray};
+use crate::model::{GPUInstance, Instance, ModelFile, ModelNode, Object};
+
+/// Index of a node in the scene
+pub type NodeId = usize;
+/// Index of an object in the scene
+pub type ObjectId = usize;
+/// Index of a model file in the scene
+pub type ModelId = usize;
+/// Instances to draw: the object, the buffer that holds the instance data and the range
+/// of instances in it
+pub type InstanceBatch<'a> = (&'a Object, &'a wgpu::Buffer, Range<u32>);
+
+/// A node of the scene graph. Its transform is relative to the parent node, so moving a
+/// node moves all of its children with it. Nodes that reference an object draw an instance
+/// of it, nodes without one only group their children.
+pub struct Node {
+    pub name: String,
+    /// position, rotation and scale relative to the parent
+    pub transform: Instance,
+    pub object: Option<ObjectId>,
+    /// the model file that the node places, see `Scene::add_model_node`
+    pub model: Option<ModelId>,
+    // whether the node was created from the nodes of the model of an ancestor
+    part_of_model: bool,
+    parent: Option<NodeId>,
+    children: Vec<NodeId>,
+    // the transform relative to the world, updated by `update_world_transforms`
+    world: Matrix4<f32>,
+}
+
+impl Node {
+    pub fn children(&self) -> &[NodeId] {
+        &self.children
+    }
+
+    /// The transform relative to the world, as of the last call to `update_world_transforms`
+    pub fn world(&self) -> Matrix4<f32> {
+        self.world
+    }
+
+    /// Whether the node comes from the node hierarchy of a model file. Such nodes are
+    /// created again from the file when a scene is loaded.
+    pub fn is_part_of_model(&self) -> bool {
+        self.part_of_model
+    }
+}
+
+// a model file that was added to the scene
+struct Model {
+    name: String,
+    file: String,
+    // the objects of the file follow each other
+    objects: Range<ObjectId>,
+    nodes: Vec<ModelNode>,
+}
+
+/// The loaded objects and the hierarchy of nodes that places them in the world.
+///
+/// All the instances live in one vertex buffer on the GPU, grouped by the object they
+/// are an instance of. `update_gpu_state` propagates the transforms down the hierarchy
+/// and sends the world transforms of all the instances to the GPU.
+///
+/// The instances of every object are sorted so that the ones inside the view frustum come
+/// first. The camera only draws those, while the shadow maps still draw all of them, as
+/// objects outside of the view can cast shadows into it.
+pub struct Scene {
+    objects: Vec<Object>,
+    // the name and the file of every model, so the scene can be written to a scene file
+    models: Vec<Model>,
+    // the model that every object was loaded from
+    object_models: Vec<ModelId>,
+    // removed nodes leave a hole so that the ids of the other nodes stay valid
+    nodes: Vec<Option<Node>>,
+    roots: Vec<NodeId>,
+    instance_buffer: wgpu::Buffer,
+    // number of instances that fit into the buffer
+    capacity: usize,
+    // the range of the instance buffer that belongs to each object
+    batches: Vec<Range<u32>>,
+    // the number of instances at the start of each batch that are inside the frustum
+    visible: Vec<u32>,
+    culled_count: usize,
+    // where the instance of every node ended up in the instance buffer
+    instance_indices: Vec<Option<u32>>,
+}
+
+impl Scene {
+    pub fn new(device: &wgpu::Device) -> Self {
+        let capacity = 64;
+        Self {
+            objects: Vec::new(),
+            models: Vec::new(),
+            object_models: Vec::new(),
+            nodes: Vec::new(),
+            roots: Vec::new(),
+            instance_buffer: Self::create_buffer(device, capacity),
+            capacity,
+            batches: Vec::new(),
+            visible: Vec::new(),
+            culled_count: 0,
+            instance_indices: Vec::new(),
+        }
+    }
+
+    fn create_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Instance Buffer"),
+            size: (capacity * mem::size_of::<GPUInstance>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    /// Add the objects and nodes that were loaded from `file` so that nodes can place them
+    pub fn add_model(&mut self, name: &str, file: &str, model: ModelFile) -> ModelId {
+        let id = self.models.len();
+        let start = self.objects.len();
+        self.object_models.extend(model.objects.iter().map(|_| id));
+        self.objects.extend(model.objects);
+        self.models.push(Model {
+            name: name.to_string(),
+            file: file.to_string(),
+            objects: start..self.objects.len(),
+            nodes: model.nodes,
+        });
+        id
+    }
+
+    /// The name and the file every model was added with
+    pub fn models(&self) -> impl Iterator<Item = (ModelId, &str, &str)> {
+        self.models.iter().enumerate().map(|(id, model)| (id, model.name.as_str(), model.file.as_str()))
+    }
+
+    /// The object of a model that is a single object without a transform of its own, like
+    /// every OBJ file
+    pub fn model_object(&self, id: ModelId) -> Option<ObjectId> {
+        let model = &self.models[id];
+        match model.nodes.as_slice() {
+            [node] if node.children.is_empty() && node.transform.to_matrix() == Matrix4::identity() => {
+                node.object.map(|object| model.objects.start + object)
+            }
+            _ => None,
+        }
+    }
+
+    /// The model that an object was loaded from
+    pub fn object_model(&self, id: ObjectId) -> ModelId {
+        self.object_models[id]
+    }
+
+    /// Add a node below `parent` that places a model. A model that is a single object
+    /// becomes the object of the node, otherwise the nodes of the model are added as
+    /// children of the node.
+    pub fn add_model_node(&mut self, name: &str, parent: Option<NodeId>, transform: Instance, model: ModelId) -> NodeId {
+        let id = self.add_node(name, parent, transform, self.model_object(model));
+        self.node_mut(id).model = Some(model);
+        if self.node(id).object.is_none() {
+            // taken out while the nodes are added, as adding them needs the whole scene
+            let nodes = mem::take(&mut self.models[model].nodes);
+            let first_object = self.models[model].objects.start;
+            let mut stack = nodes.iter().rev().map(|node| (node, id)).collect::<Vec<_>>();
+            while let Some((node, parent)) = stack.pop() {
+                let object = node.object.map(|object| first_object + object);
+                let child = self.add_node(&node.name, Some(parent), node.transform.clone(), object);
+                self.node_mut(child).part_of_model = true;
+                // reversed so the children keep their order
+                stack.extend(node.children.iter().rev().map(|node| (node, child)));
+            }
+            self.models[model].nodes = nodes;
+        }
+        id
+    }
+
+    /// Add a node below `parent`, or as a root node if there is no parent
+    pub fn add_node(
+        &mut self,
+        name: &str,
+        parent: Option<NodeId>,
+        transform: Instance,
+        object: Option<ObjectId>,
+    ) -> NodeId {
+        if let Some(object) = object {
+            assert!(object < self.objects.len(), "object {} is not part of the scene", object);
+        }
+        let id = self.nodes.len();
+        self.nodes.push(Some(Node {
+            name: name.to_string(),
+            transform,
+            object,
+            model: None,
+            part_of_model: false,
+            parent,
+            children: Vec::new(),
+            world: Matrix4::identity(),
+        }));
+        match parent {
+            Some(parent) => self.node_mut(parent).children.push(id),
+            None => self.roots.push(id),
+        }
+        id
+    }
+
+    /// Remove a node together with all of its children
+    pub fn remove_node(&mut self, id: NodeId) {
+        match self.node(id).parent {
+            Some(parent) => self.node_mut(parent).children.retain(|&child| child != id),
+            None => self.roots.retain(|&root| root != id),
+        }
+        let mut stack = vec![id];
+        while let Some(id) = stack.pop() {
+            let node = self.nodes[id].take().expect("node was already removed");
+            stack.extend(node.children);
+        }
+    }
+
+    /// Whether the node is still part of the scene
+    pub fn contains(&self, id: NodeId) -> bool {
+        self.nodes.get(id).is_some_and(|node| node.is_some())
+    }
+
+    pub fn node(&self, id: NodeId) -> &Node {
+        self.nodes[id].as_ref().expect("node was removed")
+    }
+
+    pub fn node_mut(&mut self, id: NodeId) -> &mut Node {
+        self.nodes[id].as_mut().expect("node was removed")
+    }
+
+    /// All the nodes that are still part of the scene
+    pub fn nodes(&self) -> impl Iterator<Item = (NodeId, &Node)> {
+        self.nodes.iter().enumerate().filter_map(|(id, node)| node.as_ref().map(|node| (id, node)))
+    }
+
+    /// The nodes without a parent
+    pub fn roots(&self) -> &[NodeId] {
+        &self.roots
+    }
+
+    pub fn object(&self, id: ObjectId) -> &Object {
+        &self.objects[id]
+    }
+
+    /// Compute the world transform of every node from the transforms of its ancestors
+    pub fn update_world_transforms(&mut self) {
+        let mut stack = self.roots.iter().map(|&root| (root, Matrix4::identity())).collect::<Vec<_>>();
+        while let Some((id, parent_world)) = stack.pop() {
+            let node = self.nodes[id].as_mut().expect("removed node is still linked");
+            node.world = parent_world * node.transform.to_matrix();
+            stack.extend(node.children.iter().map(|&child| (child, node.world)));
+        }
+    }
+
+    /// The bounds of the object of a node in world space, as of the last call to
+    /// `update_world_transforms`. Nodes without an object have no bounds.
+    pub fn world_bounds(&self, id: NodeId) -> Option<(Aabb, BoundingSphere)> {
+        let node = self.node(id);
+        let object = &self.objects[node.object?];
+        Some((object.aabb.transform(&node.world), object.bounding_sphere.transform(&node.world)))
+    }
+
+    /// Propagate the transforms and send the instances to the GPU. If there are more
+    /// instances than fit into the buffer a bigger one is created. Instances whose bounds
+    /// are outside the `frustum` are culled, without a frustum all of them are visible.
+    pub fn update_gpu_state(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, frustum: Option<&Frustum>) {
+        self.update_world_transforms();
+
+        let mut visible = vec![Vec::new(); self.objects.len()];
+        let mut culled = vec![Vec::new(); self.objects.len()];
+        for (id, node) in self.nodes() {
+            let Some(object) = node.object else { continue };
+            let is_visible = match (frustum, self.world_bounds(id)) {
+                // the sphere test is cheaper, the box is only tested if the sphere is visible
+                (Some(frustum), Some((aabb, sphere))) => frustum.intersects_sphere(&sphere) && frustum.intersects_aabb(&aabb),
+                _ => true,
+            };
+            let instances = if is_visible { &mut visible[object] } else { &mut culled[object] };
+            instances.push((id, GPUInstance::from_matrix(node.world)));
+        }
+        self.batches.clear();
+        self.visible.clear();
+        self.culled_count = 0;
+        self.instance_indices = vec![None; self.nodes.len()];
+        let mut instance_data = Vec::new();
+        for (visible, culled) in visible.into_iter().zip(culled) {
+            let start = instance_data.len() as u32;
+            self.visible.push(visible.len() as u32);
+            self.culled_count += culled.len();
+            for (id, instance) in visible.into_iter().chain(culled) {
+                self.instance_indices[id] = Some(instance_data.len() as u32);
+                instance_data.push(instance);
+            }
+            self.batches.push(start..instance_data.len() as u32);
+        }
+
+        if instance_data.len() > self.capacity {
+            self.capacity = instance_data.len().next_power_of_two();
+            self.instance_buffer = Self::create_buffer(device, self.capacity);
+        }
+        if !instance_data.is_empty() {
+            queue.write_buffer(&self.instance_buffer, 0, bytemuck::cast_slice(&instance_data));
+        }
+    }
+
+    /// The index of the instance of a node in the instance buffer, as of the last call to
+    /// `update_gpu_state`
+    pub fn instance_index(&self, id: NodeId) -> Option<u32> {
+        self.instance_indices.get(id).copied().flatten()
+    }
+
+    /// The closest instance that the ray hits and the distance to it along the ray. The
+    /// bounding boxes sort out most instances, only the remaining ones are tested triangle
+    /// by triangle.
+    pub fn pick(&self, ray: &Ray) -> Option<(NodeId, f32)> {
+        let mut closest: Option<(NodeId, f32)> = None;
+        for (id, node) in self.nodes() {
+            let (Some(object), Some((aabb, _))) = (node.object, self.world_bounds(id)) else { continue };
+            match ray.intersect_aabb(&aabb) {
+                Some(distance) if closest.is_none_or(|(_, closest)| distance < closest) => {}
+                _ => continue,
+            }
+            let Some(to_object) = node.world.invert() else { continue };
+            let local_ray = ray.transform(&to_object);
+            if let Some(distance) = self.object(object).intersect_ray(&local_ray) {
+                if closest.is_none_or(|(_, closest)| distance < closest) {
+                    closest = Some((id, distance));
+                }
+            }
+        }
+        closest
+    }
+
+    /// The number of instances that are inside and outside the frustum, as of the last call
+    /// to `update_gpu_state`
+    pub fn culling_stats(&self) -> (usize, usize) {
+        let visible = self.visible.iter().sum::<u32>() as usize;
+        (visible, self.culled_count)
+    }
+
+    /// The node hierarchy with controls for the transforms of the nodes
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        let mut remove = None;
+        for root in self.roots.clone() {
+            self.node_ui(ui, root, &mut remove);
+        }
+        if let Some(id) = remove {
+            self.remove_node(id);
+        }
+    }
+
+    fn node_ui(&mut self, ui: &mut egui::Ui, id: NodeId, remove: &mut Option<NodeId>) {
+        let name = self.node(id).name.clone();
+        egui::CollapsingHeader::new(name)
+            .id_source(("node", id))
+            .show(ui, |ui| {
+                self.node_mut(id).transform.ui(ui);
+                if let Some((aabb, _)) = self.world_bounds(id) {
+                    let size = aabb.half_extents() * 2.0;
+                    ui.label(format!("size {:.2} x {:.2} x {:.2}", size.x, size.y, size.z));
+                }
+                if ui.button("remove").clicked() {
+                    *remove = Some(id);
+                }
+                for child in self.node(id).children().to_vec() {
+                    self.node_ui(ui, child, remove);
+                }
+            });
+    }
+
+    pub fn instance_buffer(&self) -> &wgpu::Buffer {
+        &self.instance_buffer
+    }
+
+    /// Every object with the range of the instance buffer that holds its instances, as of
+    /// the last call to `update_gpu_state`. Objects without instances are left out.
+    pub fn batches(&self) -> impl Iterator<Item = (&Object, Range<u32>)> {
+        self.objects.iter().zip(self.batches.iter().cloned()).filter(|(_, batch)| !batch.is_empty())
+    }
+
+    /// Like `batches`, but only with the instances that are inside the frustum
+    pub fn visible_batches(&self) -> impl Iterator<Item = (&Object, Range<u32>)> {
+        self.objects.iter()
+            .zip(self.batches.iter().zip(&self.visible))
+            .map(|(object, (batch, &visible))| (object, batch.start..batch.start + visible))
+            .filter(|(_, batch)| !batch.is_empty())
+    }
+}
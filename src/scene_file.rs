@@ -0,0 +1,351 @@
+use std::path::Path;
+use anyhow::Context;
+use cgmath::{Deg, Rad};
+use serde::{Deserialize, Serialize};
+use crate::layout::Layout;
+use crate::light::{LightType, LightUniform};
+use crate::model::Instance;
+
+/// The description of a scene as it is stored in a scene file (in RON). It lists the
+/// models that are loaded, the node hierarchy that places instances of them, the
+/// lights and the camera.
+///
+/// ```ron
+/// (
+///     models: [(name: "cube", file: "cube.obj")],
+///     nodes: [
+///         (name: "cube", model: Some("cube"), position: (0.0, 1.0, 0.0)),
+///         (name: "grid", instances: Some((model: "cube", layout: Spiral(count: 500, spacing: 1.0)))),
+///     ],
+///     lights: [(kind: Point, position: (2.0, 2.0, 2.0), intensity: 3.0)],
+///     camera: (position: (0.0, 5.0, 10.0), yaw: -90.0, pitch: -20.0),
+/// )
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SceneDescription {
+    #[serde(default)]
+    pub models: Vec<ModelDescription>,
+    /// the root nodes, every node holds its children
+    #[serde(default)]
+    pub nodes: Vec<NodeDescription>,
+    #[serde(default)]
+    pub lights: Vec<LightDescription>,
+    #[serde(default)]
+    pub camera: CameraDescription,
+}
+
+impl SceneDescription {
+    pub fn from_ron(text: &str) -> anyhow::Result<Self> {
+        Ok(ron::from_str(text)?)
+    }
+
+    pub fn to_ron(&self) -> anyhow::Result<String> {
+        Ok(ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?)
+    }
+
+    /// Read a scene file
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).with_context(|| format!("failed to read {:?}", path))?;
+        Self::from_ron(&text).with_context(|| format!("failed to parse the scene file {:?}", path))
+    }
+
+    /// Write the scene to a file, replacing the file if it exists
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        std::fs::write(path, self.to_ron()?).with_context(|| format!("failed to write {:?}", path))
+    }
+}
+
+/// A model file that is loaded for the scene, the nodes refer to it by name
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModelDescription {
+    pub name: String,
+    /// OBJ or glTF file in the resources
+    pub file: String,
+}
+
+/// A node of the scene graph, the transform is relative to the parent. A node with a
+/// model is an instance of that model, the nodes of a glTF model are added below it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NodeDescription {
+    pub name: String,
+    #[serde(default)]
+    pub model: Option<String>,
+    /// the animated instances that are placed relative to the node
+    #[serde(default)]
+    pub instances: Option<InstancesDescription>,
+    #[serde(default)]
+    pub position: [f32; 3],
+    /// a quaternion as (x, y, z, w)
+    #[serde(default = "identity_rotation")]
+    pub rotation: [f32; 4],
+    #[serde(default = "unit_scale")]
+    pub scale: [f32; 3],
+    #[serde(default)]
+    pub children: Vec<NodeDescription>,
+}
+
+impl NodeDescription {
+    pub fn from_transform(name: &str, model: Option<String>, transform: &Instance) -> Self {
+        let r = transform.rotation;
+        Self {
+            name: name.to_string(),
+            model,
+            instances: None,
+            position: transform.position.into(),
+            rotation: [r.v.x, r.v.y, r.v.z, r.s],
+            scale: transform.scale.into(),
+            children: Vec::new(),
+        }
+    }
+
+    pub fn transform(&self) -> Instance {
+        let [x, y, z, w] = self.rotation;
+        Instance {
+            position: self.position.into(),
+            rotation: cgmath::Quaternion::new(w, x, y, z),
+            scale: self.scale.into(),
+        }
+    }
+}
+
+/// Instances of a model that the layout places and the GPU turns. Only the file of a CSV
+/// layout is stored, the positions are read from it when the scene is loaded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InstancesDescription {
+    pub model: String,
+    pub layout: Layout,
+}
+
+fn identity_rotation() -> [f32; 4] {
+    [0.0, 0.0, 0.0, 1.0]
+}
+
+fn unit_scale() -> [f32; 3] {
+    [1.0; 3]
+}
+
+/// A light, the fields that the type of the light does not use can be left out.
+/// The cone angles are in degrees.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LightDescription {
+    pub kind: LightType,
+    #[serde(default)]
+    pub position: [f32; 3],
+    #[serde(default = "down")]
+    pub direction: [f32; 3],
+    #[serde(default = "white")]
+    pub color: [f32; 3],
+    pub intensity: f32,
+    #[serde(default = "inverse_square")]
+    pub attenuation: [f32; 3],
+    #[serde(default)]
+    pub inner_cone_angle: f32,
+    #[serde(default)]
+    pub outer_cone_angle: f32,
+}
+
+impl LightDescription {
+    pub fn from_light(light: &LightUniform) -> Self {
+        Self {
+            kind: light.light_type(),
+            position: light.position,
+            direction: light.direction,
+            color: light.color,
+            intensity: light.intensity,
+            attenuation: light.attenuation,
+            inner_cone_angle: Deg::from(Rad(light.inner_cone_angle)).0,
+            outer_cone_angle: Deg::from(Rad(light.outer_cone_angle)).0,
+        }
+    }
+
+    pub fn to_light(&self) -> LightUniform {
+        let mut light = LightUniform::spot(
+            self.position,
+            self.direction,
+            Deg(self.inner_cone_angle),
+            Deg(self.outer_cone_angle),
+            self.color,
+            self.intensity,
+        );
+        light.set_light_type(self.kind);
+        light.attenuation = self.attenuation;
+        light
+    }
+}
+
+fn down() -> [f32; 3] {
+    [0.0, -1.0, 0.0]
+}
+
+fn white() -> [f32; 3] {
+    [1.0; 3]
+}
+
+fn inverse_square() -> [f32; 3] {
+    [0.0, 0.0, 1.0]
+}
+
+/// Where the camera is and where it looks, the angles are in degrees
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CameraDescription {
+    pub position: [f32; 3],
+    pub yaw: f32,
+    pub pitch: f32,
+    pub field_of_view: f32,
+    pub znear: f32,
+    pub zfar: f32,
+}
+
+impl Default for CameraDescription {
+    fn default() -> Self {
+        Self {
+            position: [0.0, 5.0, 10.0],
+            yaw: -90.0,
+            pitch: -20.0,
+            field_of_view: 45.0,
+            znear: 0.1,
+            zfar: 100.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example() -> SceneDescription {
+        SceneDescription {
+            models: vec![
+                ModelDescription { name: "cube".to_string(), file: "cube.obj".to_string() },
+                ModelDescription { name: "robot".to_string(), file: "robot.glb".to_string() },
+            ],
+            nodes: vec![NodeDescription {
+                name: "group".to_string(),
+                model: None,
+                instances: Some(InstancesDescription {
+                    model: "cube".to_string(),
+                    layout: Layout::Random { count: 1000, extent: 20.0, seed: 3 },
+                }),
+                position: [1.0, 2.0, 3.0],
+                rotation: [0.0, 0.38268343, 0.0, 0.9238795],
+                scale: [2.0, 2.0, 2.0],
+                children: vec![
+                    NodeDescription {
+                        name: "child".to_string(),
+                        model: Some("cube".to_string()),
+                        instances: None,
+                        position: [0.5, 0.0, -0.25],
+                        rotation: identity_rotation(),
+                        scale: [1.0, 0.5, 1.0],
+                        children: Vec::new(),
+                    },
+                    NodeDescription {
+                        name: "robot".to_string(),
+                        model: Some("robot".to_string()),
+                        instances: None,
+                        position: [0.0, 0.0, 0.1],
+                        rotation: identity_rotation(),
+                        scale: unit_scale(),
+                        children: Vec::new(),
+                    },
+                ],
+            }],
+            lights: vec![
+                LightDescription::from_light(&LightUniform::point([2.0, 2.0, 2.0], [1.0, 0.9, 0.8], 3.0)),
+                LightDescription::from_light(&LightUniform::directional([-0.3, -1.0, -0.2], [1.0; 3], 0.5)),
+                LightDescription {
+                    kind: LightType::Spot,
+                    position: [0.0, 4.0, 0.0],
+                    direction: down(),
+                    color: white(),
+                    intensity: 10.0,
+                    attenuation: [1.0, 0.1, 0.01],
+                    inner_cone_angle: 20.0,
+                    outer_cone_angle: 30.0,
+                },
+            ],
+            camera: CameraDescription {
+                position: [-3.0, 1.5, 7.25],
+                yaw: -75.0,
+                pitch: -10.0,
+                field_of_view: 60.0,
+                znear: 0.05,
+                zfar: 250.0,
+            },
+        }
+    }
+
+    #[test]
+    fn round_trip_through_ron() {
+        let scene = example();
+        let text = scene.to_ron().unwrap();
+        assert_eq!(SceneDescription::from_ron(&text).unwrap(), scene);
+    }
+
+    #[test]
+    fn round_trip_through_a_file() {
+        let scene = example();
+        let path = std::env::temp_dir().join(format!("scene_file_round_trip_{}.ron", std::process::id()));
+        scene.save(&path).unwrap();
+        let loaded = SceneDescription::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap(), scene);
+    }
+
+    #[test]
+    fn missing_fields_get_defaults() {
+        let scene = SceneDescription::from_ron(r#"(
+            models: [(name: "cube", file: "cube.obj")],
+            nodes: [(name: "cube", model: Some("cube"))],
+            lights: [(kind: Directional, intensity: 0.5)],
+        )"#).unwrap();
+        let node = &scene.nodes[0];
+        assert_eq!(node.position, [0.0; 3]);
+        assert_eq!(node.rotation, identity_rotation());
+        assert_eq!(node.scale, unit_scale());
+        assert_eq!(node.instances, None);
+        assert!(node.children.is_empty());
+        assert_eq!(scene.lights[0].direction, down());
+        assert_eq!(scene.lights[0].color, white());
+        assert_eq!(scene.camera, CameraDescription::default());
+        assert_eq!(scene.models[0].file, "cube.obj");
+    }
+
+    #[test]
+    fn csv_layouts_only_keep_the_file() {
+        let mut node = NodeDescription::from_transform("points", None, &example().nodes[0].transform());
+        node.instances = Some(InstancesDescription {
+            model: "cube".to_string(),
+            layout: Layout::Csv { file: "points.csv".to_string(), positions: vec![[1.0, 2.0, 3.0].into()] },
+        });
+        let text = ron::to_string(&node).unwrap();
+        assert!(text.contains("Csv(file:\"points.csv\")"), "{}", text);
+        let back: NodeDescription = ron::from_str(&text).unwrap();
+        assert_eq!(back.instances.unwrap().layout, Layout::Csv { file: "points.csv".to_string(), positions: Vec::new() });
+    }
+
+    #[test]
+    fn lights_round_trip() {
+        for light in example().lights {
+            let back = LightDescription::from_light(&light.to_light());
+            assert_eq!(back.kind, light.kind);
+            assert_eq!(back.position, light.position);
+            assert_eq!(back.direction, light.direction);
+            assert_eq!(back.attenuation, light.attenuation);
+            assert!((back.inner_cone_angle - light.inner_cone_angle).abs() < 1e-4);
+            assert!((back.outer_cone_angle - light.outer_cone_angle).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn transforms_round_trip() {
+        let node = &example().nodes[0];
+        let back = NodeDescription::from_transform(&node.name, node.model.clone(), &node.transform());
+        assert_eq!(back.position, node.position);
+        assert_eq!(back.rotation, node.rotation);
+        assert_eq!(back.scale, node.scale);
+    }
+}
@@ -0,0 +1,302 @@
+use std::mem;
+use cgmath::*;
+use crate::light::{LightType, LightUniform};
+use crate::model::{self, DrawShadow, GPUVertex, GPUInstance};
+use crate::scene::InstanceBatch;
+use crate::observer::OPENGL_TO_WGPU_MATRIX;
+use crate::texture;
+
+/// Number of layers in the shadow map. Directional and spot lights use one layer, point
+/// lights use six (one per face of a cube around the light). Lights that don't fit
+/// don't cast shadows.
+pub const SHADOW_MAP_LAYERS: u32 = 16;
+/// Width and height of a shadow map layer in texels
+pub const SHADOW_MAP_SIZE: u32 = 1024;
+/// Marker for lights that did not get a shadow map layer
+pub const NO_SHADOW: u32 = u32::MAX;
+
+const SHADOW_ZNEAR: f32 = 0.1;
+const SHADOW_ZFAR: f32 = 50.0;
+
+/// The view direction and up vector of the six faces of a point light shadow cube, in
+/// the order +X, -X, +Y, -Y, +Z, -Z. The fragment shader picks the face in the same order.
+const CUBE_FACES: [([f32; 3], [f32; 3]); 6] = [
+    ([1.0, 0.0, 0.0], [0.0, -1.0, 0.0]),
+    ([-1.0, 0.0, 0.0], [0.0, -1.0, 0.0]),
+    ([0.0, 1.0, 0.0], [0.0, 0.0, 1.0]),
+    ([0.0, -1.0, 0.0], [0.0, 0.0, -1.0]),
+    ([0.0, 0.0, 1.0], [0.0, -1.0, 0.0]),
+    ([0.0, 0.0, -1.0], [0.0, -1.0, 0.0]),
+];
+
+/// The shadow maps of all lights. Every frame the scene is rendered from the point of view
+/// of every shadow casting light into its layer(s) of a depth texture array, the fragment
+/// shader then compares the depth of a fragment against it to find out if it is lit.
+pub struct ShadowMaps {
+    // owns the array that the layer views and the bind group point into
+    _texture: texture::Texture,
+    layer_views: Vec<wgpu::TextureView>,
+    // the view projection matrices of all layers, read by the fragment shader
+    matrices_buffer: wgpu::Buffer,
+    // the same matrices spaced out so that every shadow pass can bind its own with a
+    // dynamic offset
+    pass_buffer: wgpu::Buffer,
+    pass_stride: u32,
+    pass_bind_group: wgpu::BindGroup,
+    pipeline: wgpu::RenderPipeline,
+    used_layers: u32,
+    /// half the width of the area around the origin that directional lights cast shadows in
+    pub directional_extent: f32,
+    pub bind_group_layout: wgpu::BindGroupLayout,
+    pub bind_group: wgpu::BindGroup,
+}
+
+impl ShadowMaps {
+    pub fn new(device: &wgpu::Device) -> Self {
+        let texture = texture::Texture::create_depth_texture_array(
+            device, SHADOW_MAP_SIZE, SHADOW_MAP_SIZE, SHADOW_MAP_LAYERS, 1, "shadow map");
+        let layer_views = (0..SHADOW_MAP_LAYERS).map(|layer| {
+            texture.texture.create_view(&wgpu::TextureViewDescriptor {
+                label: Some("shadow map layer"),
+                dimension: Some(wgpu::TextureViewDimension::D2),
+                base_array_layer: layer,
+                array_layer_count: Some(1),
+                ..Default::default()
+            })
+        }).collect::<Vec<_>>();
+
+        let matrix_size = mem::size_of::<[[f32; 4]; 4]>() as u32;
+        let matrices_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("shadow matrices"),
+            size: (SHADOW_MAP_LAYERS * matrix_size) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let alignment = device.limits().min_uniform_buffer_offset_alignment;
+        let pass_stride = matrix_size.div_ceil(alignment) * alignment;
+        let pass_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("shadow pass matrices"),
+            size: (SHADOW_MAP_LAYERS * pass_stride) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let pass_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("shadow pass bind group layout"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: wgpu::BufferSize::new(matrix_size as u64),
+                },
+                count: None,
+            }],
+        });
+        let pass_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("shadow pass bind group"),
+            layout: &pass_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: &pass_buffer,
+                    offset: 0,
+                    size: wgpu::BufferSize::new(matrix_size as u64),
+                }),
+            }],
+        });
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("shadow bind group layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2Array,
+                        sample_type: wgpu::TextureSampleType::Depth,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Comparison),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("shadow bind group"),
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&texture.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: matrices_buffer.as_entire_binding(),
+                },
+            ],
+        });
+
+        let pipeline = create_shadow_pipeline(device, &pass_bind_group_layout);
+
+        Self {
+            _texture: texture,
+            layer_views,
+            matrices_buffer,
+            pass_buffer,
+            pass_stride,
+            pass_bind_group,
+            pipeline,
+            used_layers: 0,
+            directional_extent: 15.0,
+            bind_group_layout,
+            bind_group,
+        }
+    }
+
+    /// Recompute the view projection matrices of all shadow map layers from the lights.
+    /// The lights need to have their shadow layers assigned already.
+    pub fn update<'a, I: Iterator<Item = &'a LightUniform>>(&mut self, lights: I, queue: &wgpu::Queue) {
+        let mut matrices = vec![Matrix4::<f32>::identity(); SHADOW_MAP_LAYERS as usize];
+        self.used_layers = 0;
+        for light in lights {
+            let Some(first_layer) = light.shadow_layer() else {
+                continue;
+            };
+            for (i, matrix) in light_view_projections(light, self.directional_extent).into_iter().enumerate() {
+                let layer = first_layer + i as u32;
+                matrices[layer as usize] = matrix;
+                self.used_layers = self.used_layers.max(layer + 1);
+            }
+        }
+        let matrices = matrices.into_iter().map(|m| -> [[f32; 4]; 4] { m.into() }).collect::<Vec<_>>();
+        queue.write_buffer(&self.matrices_buffer, 0, bytemuck::cast_slice(&matrices));
+        for (layer, matrix) in matrices.iter().enumerate().take(self.used_layers as usize) {
+            queue.write_buffer(&self.pass_buffer, (layer as u32 * self.pass_stride) as wgpu::BufferAddress, bytemuck::cast_slice(&[*matrix]));
+        }
+    }
+
+    /// Record one depth only render pass per used shadow map layer that draws all the
+    /// given instances from the point of view of the light.
+    pub fn render(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        batches: &[InstanceBatch],
+    ) {
+        for layer in 0..self.used_layers {
+            let mut shadow_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Shadow Pass"),
+                color_attachments: &[],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &self.layer_views[layer as usize],
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+            });
+            shadow_pass.set_pipeline(&self.pipeline);
+            shadow_pass.set_bind_group(0, &self.pass_bind_group, &[layer * self.pass_stride]);
+            for (object, instance_buffer, instances) in batches {
+                shadow_pass.set_vertex_buffer(1, instance_buffer.slice(..));
+                shadow_pass.draw_shadow_model_instanced(object, instances.clone());
+            }
+        }
+    }
+}
+
+/// The view projection matrices that look from the light onto the scene, one for
+/// directional and spot lights and six for point lights.
+pub fn light_view_projections(light: &LightUniform, directional_extent: f32) -> Vec<Matrix4<f32>> {
+    let position = Point3::from(light.position);
+    let direction = Vector3::from(light.direction).normalize();
+    // look_to_rh breaks down if the up vector is parallel to the view direction
+    let up = if direction.y.abs() > 0.99 { Vector3::unit_z() } else { Vector3::unit_y() };
+    match light.light_type() {
+        LightType::Directional => {
+            let e = directional_extent;
+            let eye = Point3::origin() - direction * 2.0 * e;
+            let view = Matrix4::look_to_rh(eye, direction, up);
+            vec![OPENGL_TO_WGPU_MATRIX * ortho(-e, e, -e, e, SHADOW_ZNEAR, 4.0 * e) * view]
+        }
+        LightType::Spot => {
+            let fov = Rad((2.0 * light.outer_cone_angle).clamp(0.01, 3.0));
+            let view = Matrix4::look_to_rh(position, direction, up);
+            vec![OPENGL_TO_WGPU_MATRIX * perspective(fov, 1.0, SHADOW_ZNEAR, SHADOW_ZFAR) * view]
+        }
+        LightType::Point => {
+            let projection = OPENGL_TO_WGPU_MATRIX * perspective(Deg(90.0), 1.0, SHADOW_ZNEAR, SHADOW_ZFAR);
+            CUBE_FACES.iter().map(|(face_direction, face_up)| {
+                projection * Matrix4::look_to_rh(position, Vector3::from(*face_direction), Vector3::from(*face_up))
+            }).collect()
+        }
+    }
+}
+
+fn create_shadow_pipeline(device: &wgpu::Device, pass_bind_group_layout: &wgpu::BindGroupLayout) -> wgpu::RenderPipeline {
+    let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("Shadow Pipeline Layout"),
+        bind_group_layouts: &[pass_bind_group_layout],
+        push_constant_ranges: &[],
+    });
+    let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some("Shadow Shader"),
+        source: wgpu::ShaderSource::Wgsl(include_str!("shadow.wgsl").into()),
+    });
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("Shadow Pipeline"),
+        layout: Some(&layout),
+        vertex: wgpu::VertexState {
+            module: &shader,
+            entry_point: "vs_main",
+            buffers: &[model::ModelVertex::desc(), GPUInstance::desc()],
+        },
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: Some(wgpu::Face::Back),
+            polygon_mode: wgpu::PolygonMode::Fill,
+            unclipped_depth: false,
+            conservative: false,
+        },
+        // the bias pushes the stored depth away from the light a bit, otherwise surfaces
+        // shadow themselves (shadow acne)
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: texture::Texture::DEPTH_FORMAT,
+            depth_write_enabled: true,
+            depth_compare: wgpu::CompareFunction::LessEqual,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState {
+                constant: 2,
+                slope_scale: 2.0,
+                clamp: 0.0,
+            },
+        }),
+        multisample: wgpu::MultisampleState::default(),
+        // only the depth is needed
+        fragment: None,
+        multiview: None,
+    })
+}
@@ -12,28 +12,57 @@ impl Texture {
     // grayscale image
     pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
     
+    /// The depth buffer of a render pass, it needs the same sample count as the color target
     pub fn create_depth_texture(
         device: &wgpu::Device,
-        config: &wgpu::SurfaceConfiguration,
+        width: u32,
+        height: u32,
+        sample_count: u32,
+        label: &str
+    ) -> Self {
+        Self::create_depth_texture_array(device, width, height, 1, sample_count, label)
+    }
+
+    /// A depth texture with several layers, the default view of it covers all the
+    /// layers. The sampler is a comparison sampler so that the texture can be used
+    /// as a shadow map.
+    pub fn create_depth_texture_array(
+        device: &wgpu::Device,
+        width: u32,
+        height: u32,
+        layers: u32,
+        sample_count: u32,
         label: &str
     ) -> Self {
         let size = wgpu::Extent3d {
-            width: config.width,
-            height: config.height,
-            depth_or_array_layers: 1,
+            width,
+            height,
+            depth_or_array_layers: layers,
         };
         let desc = wgpu::TextureDescriptor {
             label: Some(label),
             size, 
             mip_level_count: 1,
-            sample_count: 1,
+            sample_count,
             dimension: wgpu::TextureDimension::D2,
             format: Self::DEPTH_FORMAT,
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            // multisampled depth can't be sampled with the comparison sampler anyway
+            usage: if sample_count == 1 {
+                wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING
+            } else {
+                wgpu::TextureUsages::RENDER_ATTACHMENT
+            },
             view_formats: &[],
         };
         let texture = device.create_texture(&desc);
-        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(if layers > 1 {
+                wgpu::TextureViewDimension::D2Array
+            } else {
+                wgpu::TextureViewDimension::D2
+            }),
+            ..Default::default()
+        });
         let sampler = device.create_sampler(
             &wgpu::SamplerDescriptor { 
                 address_mode_u: wgpu::AddressMode::ClampToEdge,
@@ -51,66 +80,152 @@ impl Texture {
         Self { texture, view, sampler }
     }
 
-    #[allow(dead_code)]
     pub fn from_bytes(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         bytes: &[u8],
         label: &str,
+        is_normal_map: bool,
+        anisotropy: Option<u16>,
         ) -> Result<Self> {
         let img = image::load_from_memory(bytes)?;
-        Self::from_image(device, queue, &img, Some(label))
+        Self::from_image(device, queue, &img, Some(label), is_normal_map, anisotropy)
     }
 
+    /// Normal maps store directions and not colors, so they must not be interpreted as
+    /// Srgb, otherwise the directions get bent by the gamma curve when they are sampled.
+    ///
+    /// The texture gets a full mip chain that is generated on the CPU and a trilinear
+    /// sampler. `anisotropy` additionally turns on anisotropic filtering with up to that
+    /// many samples (1 to 16), which keeps surfaces seen at a grazing angle sharp.
     pub fn from_image(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         img: &image::DynamicImage,
-        label: Option<&str>
+        label: Option<&str>,
+        is_normal_map: bool,
+        anisotropy: Option<u16>,
     ) -> Result<Self> {
-        let rgba = img.to_rgba8();
+        let mut rgba = img.to_rgba8();
         let dimensions = img.dimensions();
         let size = wgpu::Extent3d{
             width: dimensions.0,
             height: dimensions.1,
             depth_or_array_layers: 1,
         };
+        let mip_level_count = size.max_mips(wgpu::TextureDimension::D2);
         let texture = device.create_texture(&wgpu::TextureDescriptor{
             label,
             size,
-            mip_level_count: 1,
+            mip_level_count,
             sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            format: if is_normal_map {
+                wgpu::TextureFormat::Rgba8Unorm
+            } else {
+                wgpu::TextureFormat::Rgba8UnormSrgb
+            },
             usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
             view_formats: &[],
         });
-        queue.write_texture(
-            wgpu::ImageCopyTexture {
-                aspect: wgpu::TextureAspect::All,
-                texture: &texture,
-                mip_level: 0,
-                origin: wgpu::Origin3d::ZERO,
-            },
-            &rgba,
-            wgpu::ImageDataLayout {
-                offset: 0,
-                bytes_per_row: Some(4 * dimensions.0),
-                rows_per_image: Some(dimensions.1),
-            },
-            size,
-        );
+        for mip_level in 0..mip_level_count {
+            if mip_level > 0 {
+                rgba = downsample(&rgba, !is_normal_map);
+            }
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    aspect: wgpu::TextureAspect::All,
+                    texture: &texture,
+                    mip_level,
+                    origin: wgpu::Origin3d::ZERO,
+                },
+                &rgba,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(4 * rgba.width()),
+                    rows_per_image: Some(rgba.height()),
+                },
+                size.mip_level_size(mip_level, wgpu::TextureDimension::D2),
+            );
+        }
         let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
         let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
             address_mode_u: wgpu::AddressMode::ClampToEdge,
             address_mode_v: wgpu::AddressMode::ClampToEdge,
             address_mode_w: wgpu::AddressMode::ClampToEdge,
             mag_filter: wgpu::FilterMode::Linear,
-            min_filter: wgpu::FilterMode::Nearest,
-            mipmap_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            anisotropy_clamp: anisotropy.unwrap_or(1).clamp(1, 16),
             ..Default::default()
         });
         Ok(Self{ texture, view, sampler})
     }
 
+    /// A 1x1 normal map that points straight out of the surface. This is used for
+    /// materials that do not come with a normal map so that they can be drawn with
+    /// the same pipeline.
+    pub fn flat_normal_map(device: &wgpu::Device, queue: &wgpu::Queue) -> Result<Self> {
+        Self::from_color(device, queue, [128, 128, 255, 255], "flat normal map", true)
+    }
+
+    /// A 1x1 texture of a single color, it stands in for the textures a material does not
+    /// have. With white the factors of the material are used unchanged.
+    pub fn from_color(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        color: [u8; 4],
+        label: &str,
+        is_normal_map: bool,
+    ) -> Result<Self> {
+        let img = image::DynamicImage::ImageRgba8(
+            image::RgbaImage::from_pixel(1, 1, image::Rgba(color))
+        );
+        Self::from_image(device, queue, &img, Some(label), is_normal_map, None)
+    }
+}
+
+/// Halve the size of an image (rounding down, but not below 1) by averaging blocks of
+/// 2x2 pixels. Colors are averaged in linear space, averaging the Srgb values directly
+/// would make the smaller levels darker.
+fn downsample(img: &image::RgbaImage, srgb: bool) -> image::RgbaImage {
+    let to_linear = |v: u8| {
+        let v = v as f32 / 255.0;
+        if !srgb {
+            v
+        } else if v <= 0.04045 {
+            v / 12.92
+        } else {
+            ((v + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    let from_linear = |v: f32| {
+        let v = if !srgb {
+            v
+        } else if v <= 0.0031308 {
+            v * 12.92
+        } else {
+            1.055 * v.powf(1.0 / 2.4) - 0.055
+        };
+        (v * 255.0).round().clamp(0.0, 255.0) as u8
+    };
+    let (width, height) = img.dimensions();
+    image::RgbaImage::from_fn((width / 2).max(1), (height / 2).max(1), |x, y| {
+        // with an odd size the last row or column is simply dropped
+        let mut sum = [0.0f32; 4];
+        for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            let pixel = img.get_pixel((2 * x + dx).min(width - 1), (2 * y + dy).min(height - 1));
+            for c in 0..3 {
+                sum[c] += to_linear(pixel[c]);
+            }
+            // alpha is always linear
+            sum[3] += pixel[3] as f32 / 255.0;
+        }
+        image::Rgba([
+            from_linear(sum[0] / 4.0),
+            from_linear(sum[1] / 4.0),
+            from_linear(sum[2] / 4.0),
+            (sum[3] / 4.0 * 255.0).round() as u8,
+        ])
+    })
 }
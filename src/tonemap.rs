@@ -0,0 +1,235 @@
+/// The different curves for mapping the HDR colors of the scene onto the displayable range.
+/// The discriminant is what the shader sees in `curve`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ToneMappingOperator {
+    /// Just cut off everything above 1.0
+    Clamp = 0,
+    /// x / (1 + x), compresses the highlights but washes out the colors a bit
+    Reinhard = 1,
+    /// The filmic curve of the Academy Color Encoding System (Narkowicz fit)
+    Aces = 2,
+}
+
+impl ToneMappingOperator {
+    pub const ALL: [ToneMappingOperator; 3] = [
+        ToneMappingOperator::Clamp,
+        ToneMappingOperator::Reinhard,
+        ToneMappingOperator::Aces,
+    ];
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct ToneMappingUniform {
+    exposure: f32,
+    operator: u32,
+    // struct ToneMapping in tonemap.wgsl ends after the operator, this only rounds the
+    // uniform buffer up to 16 bytes
+    _padding: [u32; 2],
+}
+
+/// The scene is not drawn directly into the output but into a floating point (HDR) target,
+/// so that colors brighter than 1.0 survive. The tone mapping pass then maps them into the
+/// output with a fullscreen triangle.
+pub struct ToneMapping {
+    pub operator: ToneMappingOperator,
+    /// scene colors are multiplied by 2^exposure before the operator is applied
+    pub exposure: f32,
+    hdr_view: wgpu::TextureView,
+    uniform_buffer: wgpu::Buffer,
+    bind_group_layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+    pipeline: wgpu::RenderPipeline,
+    dirty: bool,
+}
+
+impl ToneMapping {
+    // The format of the HDR target, every pipeline that draws the scene has to use it
+    pub const HDR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+    pub fn new(device: &wgpu::Device, output_format: wgpu::TextureFormat, width: u32, height: u32) -> Self {
+        let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("tone mapping uniform"),
+            size: std::mem::size_of::<ToneMappingUniform>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("tone mapping bind group layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
+        let hdr_view = create_hdr_view(device, width, height);
+        let bind_group = create_bind_group(device, &bind_group_layout, &hdr_view, &uniform_buffer);
+
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Tone Mapping Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Tone Mapping Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("tonemap.wgsl").into()),
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Tone Mapping Pipeline"),
+            layout: Some(&layout),
+            // the fullscreen triangle is generated in the vertex shader
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: output_format,
+                    blend: Some(wgpu::BlendState::REPLACE),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            multiview: None,
+        });
+
+        Self {
+            operator: ToneMappingOperator::Aces,
+            exposure: 0.0,
+            hdr_view,
+            uniform_buffer,
+            bind_group_layout,
+            bind_group,
+            pipeline,
+            dirty: true,
+        }
+    }
+
+    /// The view the scene has to be drawn into
+    pub fn hdr_view(&self) -> &wgpu::TextureView {
+        &self.hdr_view
+    }
+
+    pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+        self.hdr_view = create_hdr_view(device, width, height);
+        self.bind_group = create_bind_group(device, &self.bind_group_layout, &self.hdr_view, &self.uniform_buffer);
+    }
+
+    pub fn update_gpu_state(&mut self, queue: &wgpu::Queue) {
+        if !self.dirty {
+            return;
+        }
+        let uniform = ToneMappingUniform {
+            exposure: self.exposure,
+            operator: self.operator as u32,
+            _padding: [0; 2],
+        };
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+        self.dirty = false;
+    }
+
+    /// Map the HDR target into the output view
+    pub fn render(&self, encoder: &mut wgpu::CommandEncoder, output: &wgpu::TextureView) {
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Tone Mapping Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: output,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: None,
+        });
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, &self.bind_group, &[]);
+        pass.draw(0..3, 0..1);
+    }
+
+    /// Controls for the operator and the exposure
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        egui::ComboBox::from_label("tone mapping")
+            .selected_text(format!("{:?}", self.operator))
+            .show_ui(ui, |ui| {
+                for operator in ToneMappingOperator::ALL {
+                    self.dirty |= ui.selectable_value(&mut self.operator, operator, format!("{:?}", operator)).changed();
+                }
+            });
+        self.dirty |= ui.add(egui::Slider::new(&mut self.exposure, -5.0..=5.0).text("exposure (EV)")).changed();
+    }
+}
+
+fn create_hdr_view(device: &wgpu::Device, width: u32, height: u32) -> wgpu::TextureView {
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("hdr target"),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: ToneMapping::HDR_FORMAT,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+        view_formats: &[],
+    });
+    texture.create_view(&wgpu::TextureViewDescriptor::default())
+}
+
+fn create_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    hdr_view: &wgpu::TextureView,
+    uniform_buffer: &wgpu::Buffer,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("tone mapping bind group"),
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(hdr_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: uniform_buffer.as_entire_binding(),
+            },
+        ],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tone_mapping_uniform_matches_the_shader() {
+        assert_eq!(std::mem::offset_of!(ToneMappingUniform, exposure), 0);
+        assert_eq!(std::mem::offset_of!(ToneMappingUniform, operator), 4);
+        assert_eq!(std::mem::size_of::<ToneMappingUniform>(), 16);
+    }
+}
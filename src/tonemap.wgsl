@@ -0,0 +1,60 @@
+// Maps the HDR scene colors into the range that the output can display
+struct ToneMapping {
+    exposure: f32,
+    // the discriminant of ToneMappingOperator
+    curve: u32,
+}
+
+@group(0) @binding(0)
+var t_hdr: texture_2d<f32>;
+@group(0) @binding(1)
+var<uniform> tone_mapping: ToneMapping;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+};
+
+// A single triangle that covers the whole screen, the vertices are
+// (-1, -1), (3, -1) and (-1, 3) so no vertex buffer is needed
+@vertex
+fn vs_main(@builtin(vertex_index) vertex_index: u32) -> VertexOutput {
+    let x = f32(i32(vertex_index & 1u) * 4 - 1);
+    let y = f32(i32(vertex_index >> 1u) * 4 - 1);
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(x, y, 0.0, 1.0);
+    return out;
+}
+
+// Krzysztof Narkowicz's fit of the ACES filmic curve
+fn aces(x: vec3<f32>) -> vec3<f32> {
+    let a = 2.51;
+    let b = 0.03;
+    let c = 2.43;
+    let d = 0.59;
+    let e = 0.14;
+    return clamp((x * (a * x + b)) / (x * (c * x + d) + e), vec3<f32>(0.0), vec3<f32>(1.0));
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    // the output and the hdr target have the same size, so the pixels line up
+    let hdr = textureLoad(t_hdr, vec2<i32>(in.clip_position.xy), 0);
+    let color = hdr.rgb * exp2(tone_mapping.exposure);
+
+    var mapped: vec3<f32>;
+    switch tone_mapping.curve {
+        // Reinhard
+        case 1u: {
+            mapped = color / (1.0 + color);
+        }
+        // ACES
+        case 2u: {
+            mapped = aces(color);
+        }
+        // clamp
+        default: {
+            mapped = clamp(color, vec3<f32>(0.0), vec3<f32>(1.0));
+        }
+    }
+    return vec4<f32>(mapped, 1.0);
+}
@@ -20,7 +20,7 @@ pub fn create_gpu_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLay
 
 pub fn create_bind_group(device: &wgpu::Device, layout: &wgpu::BindGroupLayout, proj_buffer: &wgpu::Buffer) -> wgpu::BindGroup {
     device.create_bind_group(&wgpu::BindGroupDescriptor {
-        layout: &layout,
+        layout,
         label: Some("Observer bind group"),
         entries: &[
             wgpu::BindGroupEntry {
@@ -39,13 +39,35 @@ pub fn create_gpu_buffer(device: &wgpu::Device, projection: ViewMatrix) -> wgpu:
     })
 }
 
+/// The sample counts out of 1, 2, 4 and 8 that all of the given formats support. Without
+/// the TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES feature only the guaranteed counts can be used.
+pub fn supported_sample_counts(
+    adapter: &wgpu::Adapter,
+    device_features: wgpu::Features,
+    formats: &[wgpu::TextureFormat],
+) -> Vec<u32> {
+    [1, 2, 4, 8].into_iter().filter(|&count| {
+        formats.iter().all(|format| {
+            let features = if device_features.contains(wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES) {
+                adapter.get_texture_format_features(*format)
+            } else {
+                format.guaranteed_format_features(device_features)
+            };
+            features.flags.sample_count_supported(count)
+        })
+    }).collect()
+}
+
+/// A pipeline that draws triangles with `vs_main` and `fs_main` of the shader. With a depth
+/// format the depth is tested with the given comparison and written.
 pub fn create_render_pipeline(
     device: &wgpu::Device,
     layout: &wgpu::PipelineLayout,
     color_format: wgpu::TextureFormat,
-    depth_format: Option<wgpu::TextureFormat>,
+    depth: Option<(wgpu::TextureFormat, wgpu::CompareFunction)>,
     vertex_layout: &[wgpu::VertexBufferLayout],
     shader: wgpu::ShaderModuleDescriptor,
+    sample_count: u32,
 ) -> wgpu::RenderPipeline {
     let shader = device.create_shader_module(shader);
 
@@ -68,15 +90,15 @@ pub fn create_render_pipeline(
             // requires Feature::CONSERVATIVE_RASTERIZATION
             conservative: false,
         },
-        depth_stencil: depth_format.map(|format| wgpu::DepthStencilState {
+        depth_stencil: depth.map(|(format, depth_compare)| wgpu::DepthStencilState {
             format,
             depth_write_enabled: true,
-            depth_compare: wgpu::CompareFunction::Less,
+            depth_compare,
             stencil: wgpu::StencilState::default(),
             bias: wgpu::DepthBiasState::default(),
         }),
         multisample: wgpu::MultisampleState {
-            count: 1,
+            count: sample_count,
             mask: !0,
             alpha_to_coverage_enabled: false,
         },